@@ -0,0 +1,2 @@
+ALTER TABLE tokens ADD COLUMN encrypted_token BYTEA;
+ALTER TABLE tokens ALTER COLUMN token DROP NOT NULL;
@@ -0,0 +1 @@
+ALTER TABLE tokens ADD COLUMN key_version INTEGER NOT NULL DEFAULT 1;
@@ -0,0 +1,2 @@
+ALTER TABLE tokens ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT now();
+ALTER TABLE tokens ADD COLUMN last_used_at TIMESTAMPTZ;
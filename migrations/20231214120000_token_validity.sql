@@ -0,0 +1,2 @@
+ALTER TABLE tokens ADD COLUMN last_validated_at TIMESTAMPTZ;
+ALTER TABLE tokens ADD COLUMN invalid_since TIMESTAMPTZ;
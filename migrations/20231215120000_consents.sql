@@ -0,0 +1,5 @@
+CREATE TABLE consents (
+    chat_id BIGINT PRIMARY KEY,
+    terms_version INTEGER NOT NULL,
+    accepted_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
@@ -0,0 +1,5 @@
+CREATE TABLE admins (
+    chat_id BIGINT PRIMARY KEY,
+    granted_by BIGINT,
+    granted_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
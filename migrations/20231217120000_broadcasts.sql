@@ -0,0 +1,18 @@
+CREATE TABLE broadcasts (
+    id BIGSERIAL PRIMARY KEY,
+    author_chat_id BIGINT NOT NULL,
+    text TEXT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'queued',
+    progress_message_id INTEGER,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    finished_at TIMESTAMPTZ
+);
+
+CREATE TABLE broadcast_recipients (
+    broadcast_id BIGINT NOT NULL REFERENCES broadcasts ( id ) ON DELETE CASCADE,
+    chat_id BIGINT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'pending',
+    error TEXT,
+    updated_at TIMESTAMPTZ,
+    PRIMARY KEY ( broadcast_id, chat_id )
+);
@@ -0,0 +1,4 @@
+ALTER TABLE broadcasts ALTER COLUMN status SET DEFAULT 'draft';
+ALTER TABLE broadcasts ADD COLUMN parse_mode TEXT;
+ALTER TABLE broadcasts ADD COLUMN attachment_kind TEXT;
+ALTER TABLE broadcasts ADD COLUMN attachment_file_id TEXT;
@@ -0,0 +1,7 @@
+CREATE TABLE preferences (
+    chat_id BIGINT PRIMARY KEY,
+    broadcasts BOOLEAN NOT NULL DEFAULT TRUE,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+ALTER TABLE broadcasts ADD COLUMN critical BOOLEAN NOT NULL DEFAULT FALSE;
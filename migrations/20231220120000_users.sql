@@ -0,0 +1,12 @@
+CREATE TABLE users (
+    chat_id BIGINT PRIMARY KEY,
+    username TEXT,
+    first_name TEXT,
+    last_name TEXT,
+    language_code TEXT,
+    first_seen TIMESTAMPTZ NOT NULL DEFAULT now(),
+    last_seen TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+INSERT INTO users ( chat_id, first_seen, last_seen )
+    SELECT chat_id, created_at, COALESCE(last_used_at, created_at) FROM tokens;
@@ -0,0 +1,2 @@
+ALTER TABLE users ADD COLUMN inactive_since TIMESTAMPTZ;
+ALTER TABLE users ADD COLUMN inactive_reason TEXT;
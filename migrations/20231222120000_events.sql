@@ -0,0 +1,10 @@
+CREATE TABLE events (
+    id BIGSERIAL PRIMARY KEY,
+    chat_id BIGINT NOT NULL,
+    command TEXT NOT NULL,
+    error TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX events_created_at_idx ON events ( created_at );
+CREATE INDEX events_chat_id_idx ON events ( chat_id );
@@ -0,0 +1,6 @@
+CREATE TABLE bans (
+    chat_id BIGINT PRIMARY KEY,
+    reason TEXT,
+    banned_by BIGINT NOT NULL,
+    banned_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
@@ -0,0 +1,98 @@
+-- Same schema as the PostgreSQL migrations up to 20231223120000_bans.sql,
+-- minus columns only needed to upgrade old PostgreSQL databases.
+-- Timestamps are RFC 3339 strings in UTC, always written by the bot.
+
+CREATE TABLE tokens (
+    chat_id INTEGER PRIMARY KEY,
+    encrypted_token BLOB NOT NULL,
+    key_version INTEGER NOT NULL,
+    created_at TEXT NOT NULL,
+    last_used_at TEXT,
+    last_validated_at TEXT,
+    invalid_since TEXT
+);
+
+CREATE TABLE answers (
+    id INTEGER PRIMARY KEY,
+    question TEXT NOT NULL,
+    human TEXT NOT NULL,
+    exact TEXT NOT NULL,
+    machine TEXT NOT NULL
+);
+
+CREATE TABLE dialogues (
+    chat_id INTEGER PRIMARY KEY,
+    state TEXT NOT NULL,
+    updated_at TEXT NOT NULL
+);
+
+CREATE TABLE consents (
+    chat_id INTEGER PRIMARY KEY,
+    terms_version INTEGER NOT NULL,
+    accepted_at TEXT NOT NULL
+);
+
+CREATE TABLE admins (
+    chat_id INTEGER PRIMARY KEY,
+    granted_by INTEGER,
+    granted_at TEXT NOT NULL
+);
+
+CREATE TABLE broadcasts (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    author_chat_id INTEGER NOT NULL,
+    text TEXT NOT NULL,
+    parse_mode TEXT,
+    attachment_kind TEXT,
+    attachment_file_id TEXT,
+    critical BOOLEAN NOT NULL DEFAULT FALSE,
+    status TEXT NOT NULL DEFAULT 'draft',
+    progress_message_id INTEGER,
+    created_at TEXT NOT NULL,
+    finished_at TEXT
+);
+
+CREATE TABLE broadcast_recipients (
+    broadcast_id INTEGER NOT NULL REFERENCES broadcasts ( id ) ON DELETE CASCADE,
+    chat_id INTEGER NOT NULL,
+    status TEXT NOT NULL DEFAULT 'pending',
+    error TEXT,
+    updated_at TEXT,
+    PRIMARY KEY ( broadcast_id, chat_id )
+);
+
+CREATE TABLE preferences (
+    chat_id INTEGER PRIMARY KEY,
+    broadcasts BOOLEAN NOT NULL DEFAULT TRUE,
+    updated_at TEXT NOT NULL
+);
+
+CREATE TABLE users (
+    chat_id INTEGER PRIMARY KEY,
+    username TEXT,
+    first_name TEXT,
+    last_name TEXT,
+    language_code TEXT,
+    first_seen TEXT NOT NULL,
+    last_seen TEXT NOT NULL,
+    inactive_since TEXT,
+    inactive_reason TEXT
+);
+
+CREATE TABLE events (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    chat_id INTEGER NOT NULL,
+    command TEXT NOT NULL,
+    error TEXT,
+    created_at TEXT NOT NULL
+);
+
+CREATE INDEX events_created_at_idx ON events ( created_at );
+CREATE INDEX events_chat_id_idx ON events ( chat_id );
+
+CREATE TABLE bans (
+    chat_id INTEGER PRIMARY KEY,
+    reason TEXT,
+    banned_by INTEGER NOT NULL,
+    banned_at TEXT NOT NULL
+);
@@ -0,0 +1,311 @@
+use std::fmt::Write as _;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local, Utc};
+use teloxide::{macros::BotCommands, prelude::*};
+use tracing::*;
+
+use crate::{bans, broadcast::BroadcastQueue, consent, db::Db, retention::Retention, stats, Bot};
+
+#[derive(Debug, BotCommands, Clone)]
+#[command(rename_rule = "snake_case", description = "Команды администратора:")]
+pub enum AdminCommand {
+    #[command(description = "Отправить сообщение подписанным пользователям. \
+                       /broadcast [critical] [html|md] текст, можно ответом на фото или \
+                       документ. critical - отправить и отписавшимся.")]
+    Broadcast { message: String },
+    #[command(description = "Удалить давно не использованные токены.")]
+    PurgeTokens,
+    #[command(description = "Выдать права администратора. /grant_admin chat_id")]
+    GrantAdmin { chat_id: i64 },
+    #[command(description = "Забрать права администратора. /revoke_admin chat_id")]
+    RevokeAdmin { chat_id: i64 },
+    #[command(description = "Список администраторов.")]
+    Admins,
+    #[command(description = "Статистика использования бота.")]
+    Stats,
+    #[command(
+        description = "Запретить пользоваться ботом. /ban chat_id [причина]",
+        parse_with = bans::parse_ban
+    )]
+    Ban {
+        chat_id: i64,
+        reason: Option<String>,
+    },
+    #[command(description = "Снять запрет. /unban chat_id")]
+    Unban { chat_id: i64 },
+    #[command(description = "Список заблокированных.")]
+    Bans,
+    #[command(description = "Информация о пользователе. /user chat_id или @username")]
+    User { user: String },
+}
+
+/// How many recent commands `/user` shows.
+const USER_EVENTS_LIMIT: i64 = 10;
+
+/// Adds admins listed in `ADMIN_CHAT_IDS` (comma-separated chat ids).
+pub async fn bootstrap_from_env(db: &Db) -> Result<()> {
+    let Ok(ids) = std::env::var("ADMIN_CHAT_IDS") else {
+        warn!("ADMIN_CHAT_IDS is not set, only existing admins are kept");
+        return Ok(());
+    };
+
+    for id in ids.split(',').map(str::trim).filter(|id| !id.is_empty()) {
+        let chat_id = id
+            .parse()
+            .with_context(|| format!("invalid admin chat id in ADMIN_CHAT_IDS: {id}"))?;
+        db.add_admin(chat_id, None).await?;
+    }
+    Ok(())
+}
+
+/// dptree filter passing only messages from admins.
+pub async fn is_admin(db: Db, msg: Message) -> bool {
+    match db.is_admin(msg.chat.id.0).await {
+        Ok(admin) => admin,
+        Err(e) => {
+            error!("can't check admin rights: {:?}", e);
+            false
+        }
+    }
+}
+
+#[instrument(skip(db, bot, retention, broadcasts, msg), fields(chat_id = %msg.chat.id))]
+pub async fn answer(
+    db: Db,
+    bot: Bot,
+    retention: Retention,
+    broadcasts: BroadcastQueue,
+    msg: Message,
+    cmd: AdminCommand,
+) -> Result<()> {
+    match cmd {
+        AdminCommand::Broadcast { message } => {
+            broadcasts.create_draft(&db, &bot, &msg, &message).await?;
+        }
+        AdminCommand::PurgeTokens => {
+            let purged = retention.run(&db).await?;
+            bot.send_message(
+                msg.chat.id,
+                format!(
+                    "Удалено токенов, не использованных {} дней: {purged}.",
+                    retention.days
+                ),
+            )
+            .await?;
+        }
+        AdminCommand::GrantAdmin { chat_id } => {
+            if db.add_admin(chat_id, Some(msg.chat.id.0)).await? {
+                info!("{} granted admin to {chat_id}", msg.chat.id);
+                bot.send_message(msg.chat.id, format!("{chat_id} теперь администратор."))
+                    .await?;
+            } else {
+                bot.send_message(msg.chat.id, format!("{chat_id} уже администратор."))
+                    .await?;
+            }
+        }
+        AdminCommand::RevokeAdmin { chat_id } => {
+            if chat_id == msg.chat.id.0 {
+                bot.send_message(msg.chat.id, "Нельзя забрать права у самого себя.")
+                    .await?;
+            } else if db.remove_admin(chat_id).await? {
+                info!("{} revoked admin from {chat_id}", msg.chat.id);
+                bot.send_message(msg.chat.id, format!("{chat_id} больше не администратор."))
+                    .await?;
+            } else {
+                bot.send_message(msg.chat.id, format!("{chat_id} не администратор."))
+                    .await?;
+            }
+        }
+        AdminCommand::Admins => {
+            let admins = db.get_admins().await?;
+            let list: Vec<String> = admins.iter().map(|a| a.to_string()).collect();
+            bot.send_message(msg.chat.id, format!("Администраторы:\n{}", list.join("\n")))
+                .await?;
+        }
+        AdminCommand::Ban { chat_id, reason } => {
+            if db.is_admin(chat_id).await? {
+                bot.send_message(msg.chat.id, "Нельзя заблокировать администратора.")
+                    .await?;
+            } else if db
+                .add_ban(chat_id, reason.as_deref(), msg.chat.id.0)
+                .await?
+            {
+                info!("{} banned {chat_id}", msg.chat.id);
+                bot.send_message(msg.chat.id, format!("{chat_id} заблокирован."))
+                    .await?;
+            } else {
+                bot.send_message(msg.chat.id, format!("{chat_id} уже заблокирован."))
+                    .await?;
+            }
+        }
+        AdminCommand::Unban { chat_id } => {
+            if db.remove_ban(chat_id).await? {
+                info!("{} unbanned {chat_id}", msg.chat.id);
+                bot.send_message(msg.chat.id, format!("{chat_id} разблокирован."))
+                    .await?;
+            } else {
+                bot.send_message(msg.chat.id, format!("{chat_id} не заблокирован."))
+                    .await?;
+            }
+        }
+        AdminCommand::Bans => {
+            let bans = db.get_bans().await?;
+            if bans.is_empty() {
+                bot.send_message(msg.chat.id, "Заблокированных нет.")
+                    .await?;
+            } else {
+                let list: Vec<String> = bans
+                    .iter()
+                    .map(|b| {
+                        format!(
+                            "{} - {} ({}, заблокировал {})",
+                            b.chat_id,
+                            b.reason.as_deref().unwrap_or("без причины"),
+                            b.banned_at.format("%d.%m.%Y"),
+                            b.banned_by
+                        )
+                    })
+                    .collect();
+                bot.send_message(msg.chat.id, format!("Заблокированы:\n{}", list.join("\n")))
+                    .await?;
+            }
+        }
+        AdminCommand::User { user } => {
+            let user = user.trim();
+            let chat_id = match user.strip_prefix('@') {
+                Some(username) => db.find_user_by_username(username).await?,
+                None => user.parse().ok(),
+            };
+            match chat_id {
+                Some(chat_id) => {
+                    bot.send_message(msg.chat.id, describe_user(&db, chat_id).await?)
+                        .await?;
+                }
+                None => {
+                    bot.send_message(msg.chat.id, format!("Пользователь {user} не найден."))
+                        .await?;
+                }
+            }
+        }
+        AdminCommand::Stats => {
+            bot.send_message(msg.chat.id, stats::report(&db).await?)
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+fn format_time(time: DateTime<Utc>) -> String {
+    time.with_timezone(&Local)
+        .format("%d.%m.%Y %H:%M")
+        .to_string()
+}
+
+/// Text of the `/user` admin command.
+async fn describe_user(db: &Db, chat_id: i64) -> Result<String> {
+    let summary = db.get_user_summary(chat_id, USER_EVENTS_LIMIT).await?;
+
+    let mut text = format!("Пользователь {chat_id}\n");
+    match &summary.profile {
+        Some(profile) => {
+            let name: Vec<&str> = [&profile.first_name, &profile.last_name]
+                .into_iter()
+                .filter_map(|n| n.as_deref())
+                .collect();
+            writeln!(
+                text,
+                "Имя: {}, username: {}, язык: {}",
+                name.join(" "),
+                profile.username.as_deref().unwrap_or("-"),
+                profile.language_code.as_deref().unwrap_or("-"),
+            )?;
+            writeln!(
+                text,
+                "Первое сообщение: {}, последнее: {}",
+                format_time(profile.first_seen),
+                format_time(profile.last_seen),
+            )?;
+            if let Some(since) = profile.inactive_since {
+                writeln!(
+                    text,
+                    "Недоступен с {} ({})",
+                    format_time(since),
+                    profile.inactive_reason.as_deref().unwrap_or("-"),
+                )?;
+            }
+        }
+        None => writeln!(text, "Профиль не сохранён")?,
+    }
+
+    match &summary.token {
+        Some(token) => {
+            match token.invalid_since {
+                Some(since) => {
+                    writeln!(text, "Токен недействителен с {}", format_time(since))?;
+                }
+                None => writeln!(
+                    text,
+                    "Токен действителен, проверен: {}",
+                    token.last_validated_at.map_or("-".to_owned(), format_time),
+                )?,
+            }
+            writeln!(
+                text,
+                "Вход: {}, последнее использование: {}",
+                format_time(token.created_at),
+                token.last_used_at.map_or("-".to_owned(), format_time),
+            )?;
+        }
+        None => writeln!(text, "Токена нет")?,
+    }
+
+    match &summary.consent {
+        Some(c) if c.terms_version == consent::TERMS_VERSION => {
+            writeln!(text, "Условия приняты {}", format_time(c.accepted_at))?;
+        }
+        Some(c) => writeln!(
+            text,
+            "Приняты устаревшие условия версии {} ({})",
+            c.terms_version,
+            format_time(c.accepted_at)
+        )?,
+        None => writeln!(text, "Условия не приняты")?,
+    }
+
+    writeln!(
+        text,
+        "Рассылки: {}",
+        if summary.broadcasts {
+            "подписан"
+        } else {
+            "отписан"
+        }
+    )?;
+
+    match &summary.ban {
+        Some(ban) => writeln!(
+            text,
+            "Заблокирован {}: {}",
+            format_time(ban.banned_at),
+            ban.reason.as_deref().unwrap_or("без причины")
+        )?,
+        None => writeln!(text, "Не заблокирован")?,
+    }
+
+    if summary.recent_events.is_empty() {
+        writeln!(text, "\nКоманд не было")?;
+    } else {
+        writeln!(text, "\nПоследние команды:")?;
+        for event in &summary.recent_events {
+            write!(text, "{} /{}", format_time(event.created_at), event.command)?;
+            if let Some(error) = &event.error {
+                write!(text, " - {error}")?;
+            }
+            writeln!(text)?;
+        }
+    }
+
+    Ok(text)
+}
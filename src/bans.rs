@@ -0,0 +1,70 @@
+use teloxide::{prelude::*, utils::command::ParseError};
+use tracing::*;
+
+use crate::{
+    db::{self, Db},
+    Bot,
+};
+
+/// `/ban chat_id [reason]`, reason may contain spaces.
+pub fn parse_ban(input: String) -> Result<(i64, Option<String>), ParseError> {
+    let input = input.trim();
+    let (chat_id, reason) = input.split_once(char::is_whitespace).unwrap_or((input, ""));
+    if chat_id.is_empty() {
+        return Err(ParseError::TooFewArguments {
+            expected: 1,
+            found: 0,
+            message: "Expected chat id".to_owned(),
+        });
+    }
+    let chat_id = chat_id
+        .parse()
+        .map_err(|e| ParseError::IncorrectFormat(Box::new(e)))?;
+    let reason = reason.trim();
+    Ok((chat_id, (!reason.is_empty()).then(|| reason.to_owned())))
+}
+
+/// dptree filter passing only messages from banned chats, with their ban.
+pub async fn find_ban(db: Db, msg: Message) -> Option<db::Ban> {
+    get_ban(&db, msg.chat.id).await
+}
+
+/// Same as [`find_ban`] for button presses.
+pub async fn find_callback_ban(db: Db, q: CallbackQuery) -> Option<db::Ban> {
+    get_ban(&db, q.from.id.into()).await
+}
+
+async fn get_ban(db: &Db, chat_id: ChatId) -> Option<db::Ban> {
+    match db.get_ban(chat_id.0).await {
+        Ok(ban) => ban,
+        Err(e) => {
+            error!("can't check ban: {:?}", e);
+            None
+        }
+    }
+}
+
+/// Politely refuses to handle messages from banned chats. Messages in groups
+/// are dropped silently.
+#[instrument(skip(bot, msg), fields(chat_id = %msg.chat.id))]
+pub async fn refuse(bot: Bot, msg: Message, ban: db::Ban) -> anyhow::Result<()> {
+    if !msg.chat.is_private() {
+        return Ok(());
+    }
+
+    bot.send_message(msg.chat.id, refusal(ban)).await?;
+    Ok(())
+}
+
+#[instrument(skip(bot, q), fields(chat_id = %q.from.id))]
+pub async fn refuse_callback(bot: Bot, q: CallbackQuery, ban: db::Ban) -> anyhow::Result<()> {
+    bot.answer_callback_query(q.id).text(refusal(ban)).await?;
+    Ok(())
+}
+
+fn refusal(ban: db::Ban) -> String {
+    match ban.reason {
+        Some(reason) => format!("Доступ к боту для вас ограничен. Причина: {reason}"),
+        None => "Доступ к боту для вас ограничен.".to_owned(),
+    }
+}
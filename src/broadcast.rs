@@ -0,0 +1,434 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use teloxide::{
+    prelude::*,
+    types::{InlineKeyboardButton, InlineKeyboardMarkup, InputFile, MessageId, ParseMode},
+    RequestError,
+};
+use tokio::sync::Notify;
+use tracing::*;
+
+use crate::{
+    db::{self, Db},
+    users, Bot,
+};
+
+/// How many recipients are loaded from the database at once.
+const BATCH_SIZE: i64 = 100;
+/// How often to check for queued broadcasts if nobody woke the worker up.
+const POLL_INTERVAL: Duration = Duration::from_secs(60);
+/// Minimal interval between edits of the progress message.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(3);
+
+/// Delivery status of a broadcast to a single recipient.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryStatus {
+    Delivered,
+    /// The bot can't write to the chat, see [`users::inactive_reason`]
+    Blocked,
+    Failed,
+}
+
+impl DeliveryStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Delivered => "delivered",
+            Self::Blocked => "blocked",
+            Self::Failed => "failed",
+        }
+    }
+}
+
+const CALLBACK_PREFIX: &str = "broadcast:";
+
+/// Broadcast parsed from `/broadcast [critical] [html|md] text`. Sent as a
+/// reply to a photo or document, the broadcast includes it, caption is used if
+/// there is no text.
+#[derive(Debug)]
+struct Draft {
+    text: String,
+    parse_mode: Option<ParseMode>,
+    attachment: Option<(&'static str, String)>,
+    critical: bool,
+}
+
+impl Draft {
+    fn parse(msg: &Message, args: &str) -> Self {
+        let mut text = args.trim();
+        let mut parse_mode = None;
+        let mut critical = false;
+        loop {
+            let (word, rest) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+            match word {
+                "html" => parse_mode = Some(ParseMode::Html),
+                "md" => parse_mode = Some(ParseMode::MarkdownV2),
+                "critical" => critical = true,
+                _ => break,
+            }
+            text = rest.trim_start();
+        }
+
+        let reply = msg.reply_to_message();
+        let attachment = reply.and_then(|r| {
+            if let Some(photo) = r.photo().and_then(|p| p.last()) {
+                Some(("photo", photo.file.id.clone()))
+            } else {
+                r.document().map(|d| ("document", d.file.id.clone()))
+            }
+        });
+
+        let mut text = text.trim().to_owned();
+        if text.is_empty() {
+            if let Some(r) = reply {
+                text = r.text().or(r.caption()).unwrap_or_default().to_owned();
+            }
+        }
+
+        Self {
+            text,
+            parse_mode,
+            attachment,
+            critical,
+        }
+    }
+}
+
+fn parse_mode_to_str(parse_mode: ParseMode) -> &'static str {
+    match parse_mode {
+        ParseMode::Html => "html",
+        _ => "markdownv2",
+    }
+}
+
+fn parse_mode_from_str(parse_mode: &str) -> Option<ParseMode> {
+    match parse_mode {
+        "html" => Some(ParseMode::Html),
+        "markdownv2" => Some(ParseMode::MarkdownV2),
+        _ => None,
+    }
+}
+
+/// Sends broadcast content to `chat_id`, with `markup` for preview.
+async fn send(
+    bot: &Bot,
+    chat_id: ChatId,
+    broadcast: &db::Broadcast,
+    markup: Option<InlineKeyboardMarkup>,
+) -> Result<(), RequestError> {
+    let parse_mode = broadcast
+        .parse_mode
+        .as_deref()
+        .and_then(parse_mode_from_str);
+    let text = broadcast.text.clone();
+
+    match (
+        broadcast.attachment_kind.as_deref(),
+        broadcast.attachment_file_id.clone(),
+    ) {
+        (Some("photo"), Some(file_id)) => {
+            let mut request = bot.send_photo(chat_id, InputFile::file_id(file_id));
+            if !text.is_empty() {
+                request = request.caption(text);
+            }
+            if let Some(parse_mode) = parse_mode {
+                request = request.parse_mode(parse_mode);
+            }
+            if let Some(markup) = markup {
+                request = request.reply_markup(markup);
+            }
+            request.await?;
+        }
+        (Some("document"), Some(file_id)) => {
+            let mut request = bot.send_document(chat_id, InputFile::file_id(file_id));
+            if !text.is_empty() {
+                request = request.caption(text);
+            }
+            if let Some(parse_mode) = parse_mode {
+                request = request.parse_mode(parse_mode);
+            }
+            if let Some(markup) = markup {
+                request = request.reply_markup(markup);
+            }
+            request.await?;
+        }
+        _ => {
+            let mut request = bot.send_message(chat_id, text);
+            if let Some(parse_mode) = parse_mode {
+                request = request.parse_mode(parse_mode);
+            }
+            if let Some(markup) = markup {
+                request = request.reply_markup(markup);
+            }
+            request.await?;
+        }
+    }
+    Ok(())
+}
+
+/// Wakes the worker up when a new broadcast is queued.
+#[derive(Debug, Clone, Default)]
+pub struct BroadcastQueue(Arc<Notify>);
+
+impl BroadcastQueue {
+    /// Stores the broadcast as a draft and sends preview with confirmation
+    /// buttons to the author.
+    pub async fn create_draft(&self, db: &Db, bot: &Bot, msg: &Message, args: &str) -> Result<()> {
+        let draft = Draft::parse(msg, args);
+        if draft.text.is_empty() && draft.attachment.is_none() {
+            bot.send_message(
+                msg.chat.id,
+                "Пустая рассылка. Использование: /broadcast [critical] [html|md] текст, \
+                 можно ответом на фото или документ.",
+            )
+            .await?;
+            return Ok(());
+        }
+
+        let id = db
+            .create_broadcast(db::NewBroadcast {
+                author_chat_id: msg.chat.id.0,
+                text: &draft.text,
+                parse_mode: draft.parse_mode.map(parse_mode_to_str),
+                attachment_kind: draft.attachment.as_ref().map(|(kind, _)| *kind),
+                attachment_file_id: draft.attachment.as_ref().map(|(_, id)| id.as_str()),
+                critical: draft.critical,
+            })
+            .await?;
+        let broadcast = db
+            .get_broadcast(id)
+            .await?
+            .context("broadcast must exist")?;
+
+        let keyboard = InlineKeyboardMarkup::new([[
+            InlineKeyboardButton::callback("Отправить", format!("{CALLBACK_PREFIX}send:{id}")),
+            InlineKeyboardButton::callback("Отменить", format!("{CALLBACK_PREFIX}cancel:{id}")),
+        ]]);
+        if let Err(e) = send(bot, msg.chat.id, &broadcast, Some(keyboard)).await {
+            db.cancel_broadcast(id).await?;
+            bot.send_message(msg.chat.id, format!("Не удалось показать рассылку: {e}"))
+                .await?;
+        } else if draft.critical {
+            bot.send_message(
+                msg.chat.id,
+                "Рассылка критическая, её получат и отписавшиеся пользователи.",
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Queues confirmed broadcast, sends progress message to the author and
+    /// wakes the worker up.
+    async fn queue(&self, db: &Db, bot: &Bot, broadcast: &db::Broadcast) -> Result<bool> {
+        let author = ChatId(broadcast.author_chat_id);
+        let recipients = db.get_broadcast_recipients(broadcast.critical).await?;
+        let progress = bot
+            .send_message(
+                author,
+                format!(
+                    "Рассылка #{} поставлена в очередь, получателей: {}.",
+                    broadcast.id,
+                    recipients.len()
+                ),
+            )
+            .await?;
+
+        if !db
+            .queue_broadcast(broadcast.id, progress.id.0, &recipients)
+            .await?
+        {
+            bot.delete_message(author, progress.id).await?;
+            return Ok(false);
+        }
+        info!(
+            "queued broadcast {} to {} users",
+            broadcast.id,
+            recipients.len()
+        );
+
+        self.0.notify_one();
+        Ok(true)
+    }
+
+    /// Runs broadcasts one by one in background. Unfinished broadcasts are
+    /// resumed after restart, messages are rate limited by [`Bot`] throttling.
+    pub fn spawn_worker(&self, db: Db, bot: Bot) {
+        let notify = self.0.clone();
+        tokio::spawn(async move {
+            loop {
+                match db.next_broadcast().await {
+                    Ok(Some(broadcast)) => {
+                        if let Err(e) = run(&db, &bot, &broadcast).await {
+                            error!("broadcast {} failed: {:?}", broadcast.id, e);
+                            sentry::integrations::anyhow::capture_anyhow(&e);
+                            tokio::time::sleep(POLL_INTERVAL).await;
+                        }
+                    }
+                    Ok(None) => {
+                        let _ = tokio::time::timeout(POLL_INTERVAL, notify.notified()).await;
+                    }
+                    Err(e) => {
+                        error!("can't get next broadcast: {:?}", e);
+                        tokio::time::sleep(POLL_INTERVAL).await;
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[instrument(skip(db, bot))]
+async fn run(db: &Db, bot: &Bot, broadcast: &db::Broadcast) -> Result<()> {
+    db.set_broadcast_status(broadcast.id, "running").await?;
+
+    let mut last_progress = Instant::now();
+    loop {
+        let recipients = db.get_pending_recipients(broadcast.id, BATCH_SIZE).await?;
+        if recipients.is_empty() {
+            break;
+        }
+
+        for chat_id in recipients {
+            let (status, error) = deliver(bot, ChatId(chat_id), broadcast).await;
+            if status == DeliveryStatus::Blocked {
+                db.mark_user_inactive(chat_id, error.as_deref().unwrap_or_default())
+                    .await?;
+            }
+            db.set_recipient_status(broadcast.id, chat_id, status.as_str(), error.as_deref())
+                .await?;
+
+            if last_progress.elapsed() >= PROGRESS_INTERVAL {
+                report_progress(db, bot, broadcast, false).await?;
+                last_progress = Instant::now();
+            }
+        }
+    }
+
+    db.set_broadcast_status(broadcast.id, "finished").await?;
+    report_progress(db, bot, broadcast, true).await?;
+    info!("broadcast {} finished", broadcast.id);
+    Ok(())
+}
+
+async fn deliver(
+    bot: &Bot,
+    chat_id: ChatId,
+    broadcast: &db::Broadcast,
+) -> (DeliveryStatus, Option<String>) {
+    loop {
+        match send(bot, chat_id, broadcast, None).await {
+            Ok(_) => return (DeliveryStatus::Delivered, None),
+            Err(RequestError::RetryAfter(after)) => {
+                warn!("hit telegram flood limit, sleeping for {:?}", after);
+                tokio::time::sleep(after).await;
+            }
+            Err(e) => {
+                return match users::inactive_reason(&e) {
+                    Some(reason) => (DeliveryStatus::Blocked, Some(reason.to_owned())),
+                    None => (DeliveryStatus::Failed, Some(e.to_string())),
+                }
+            }
+        }
+    }
+}
+
+async fn report_progress(
+    db: &Db,
+    bot: &Bot,
+    broadcast: &db::Broadcast,
+    finished: bool,
+) -> Result<()> {
+    let Some(message_id) = broadcast.progress_message_id else {
+        return Ok(());
+    };
+
+    let counts = db.get_broadcast_counts(broadcast.id).await?;
+    let text = if finished {
+        format!(
+            "Рассылка #{} завершена.\nДоставлено: {}\nНедоступны: {}\nОшибок: {}",
+            broadcast.id, counts.delivered, counts.blocked, counts.failed
+        )
+    } else {
+        format!(
+            "Рассылка #{}: обработано {} из {}.\nДоставлено: {}\nНедоступны: {}\nОшибок: {}",
+            broadcast.id,
+            counts.total - counts.pending,
+            counts.total,
+            counts.delivered,
+            counts.blocked,
+            counts.failed
+        )
+    };
+
+    if let Err(e) = bot
+        .edit_message_text(
+            ChatId(broadcast.author_chat_id),
+            MessageId(message_id),
+            text,
+        )
+        .await
+    {
+        warn!("can't update broadcast progress: {}", e);
+    }
+    Ok(())
+}
+
+pub fn is_broadcast_callback(q: CallbackQuery) -> bool {
+    q.data
+        .as_deref()
+        .is_some_and(|d| d.starts_with(CALLBACK_PREFIX))
+}
+
+/// Handles "Send" and "Cancel" buttons under broadcast preview.
+#[instrument(skip(db, bot, broadcasts, q), fields(chat_id = %q.from.id))]
+pub async fn handle_callback(
+    db: Db,
+    bot: Bot,
+    broadcasts: BroadcastQueue,
+    q: CallbackQuery,
+) -> Result<()> {
+    let Some((action, id)) = q
+        .data
+        .as_deref()
+        .and_then(|d| d.strip_prefix(CALLBACK_PREFIX))
+        .and_then(|d| d.split_once(':'))
+        .and_then(|(action, id)| Some((action.to_owned(), id.parse::<i64>().ok()?)))
+    else {
+        bot.answer_callback_query(q.id).await?;
+        return Ok(());
+    };
+
+    if !db.is_admin(ChatId::from(q.from.id).0).await? {
+        bot.answer_callback_query(q.id).await?;
+        return Ok(());
+    }
+
+    let Some(broadcast) = db.get_broadcast(id).await? else {
+        bot.answer_callback_query(q.id)
+            .text("Рассылка не найдена.")
+            .await?;
+        return Ok(());
+    };
+
+    let done = match action.as_str() {
+        "send" => broadcasts.queue(&db, &bot, &broadcast).await?,
+        "cancel" => db.cancel_broadcast(id).await?,
+        _ => false,
+    };
+    let answer = match (action.as_str(), done) {
+        ("send", true) => "Рассылка поставлена в очередь.",
+        ("cancel", true) => "Рассылка отменена.",
+        _ => "Рассылка уже отправлена или отменена.",
+    };
+    bot.answer_callback_query(q.id).text(answer).await?;
+
+    if let Some(message) = q.message {
+        bot.edit_message_reply_markup(message.chat.id, message.id)
+            .await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,88 @@
+use teloxide::{
+    prelude::*,
+    types::{InlineKeyboardButton, InlineKeyboardMarkup},
+};
+use tracing::*;
+
+use crate::{db::Db, Bot};
+
+/// Bump when [`TERMS_TEXT`] changes, users will be asked to accept it again.
+pub const TERMS_VERSION: i32 = 3;
+
+const TERMS_TEXT: &str = "\
+Бот для своей работы сохраняет от пользователя только следующие данные:\n\
+- токен для доступа к заданиям (в зашифрованном виде), потому что задания \
+уникальны для каждого и для получения точных ответов необходим прямой доступ \
+к ним;\n\
+- ответы на задания (на случай если способ получения ответов исправят);\n\
+- имя пользователя, имя и язык из профиля телеграма, время первого и \
+последнего сообщения боту;\n\
+- история использованных команд с временем и видом ошибки, для статистики;\n\
+- настройки рассылок и статус доставки каждой рассылки.\n\n\
+Посмотреть сохранённые данные можно командой /mydata, выйти из аккаунта - \
+/logout, удалить все данные - /forget_me.\n\n\
+Чтобы войти в аккаунт, примите эти условия.";
+
+const CALLBACK_PREFIX: &str = "consent:";
+
+pub async fn has_consent(db: &Db, chat_id: ChatId) -> anyhow::Result<bool> {
+    Ok(db.get_consent_version(chat_id.0).await? == Some(TERMS_VERSION))
+}
+
+/// Whether the chat has accepted an older version of the terms and must
+/// accept the current one before using the bot.
+pub async fn is_outdated(db: &Db, chat_id: ChatId) -> anyhow::Result<bool> {
+    Ok(db
+        .get_consent_version(chat_id.0)
+        .await?
+        .is_some_and(|v| v < TERMS_VERSION))
+}
+
+pub async fn send_terms(bot: &Bot, chat_id: ChatId) -> anyhow::Result<()> {
+    let keyboard = InlineKeyboardMarkup::new([[InlineKeyboardButton::callback(
+        "Принимаю",
+        format!("{CALLBACK_PREFIX}{TERMS_VERSION}"),
+    )]]);
+    bot.send_message(chat_id, TERMS_TEXT)
+        .reply_markup(keyboard)
+        .await?;
+    Ok(())
+}
+
+pub fn is_consent_callback(q: CallbackQuery) -> bool {
+    q.data
+        .as_deref()
+        .is_some_and(|d| d.starts_with(CALLBACK_PREFIX))
+}
+
+#[instrument(skip(db, bot, q), fields(chat_id = %q.from.id))]
+pub async fn handle_callback(db: Db, bot: Bot, q: CallbackQuery) -> anyhow::Result<()> {
+    let version = q
+        .data
+        .as_deref()
+        .and_then(|d| d.strip_prefix(CALLBACK_PREFIX))
+        .and_then(|v| v.parse::<i32>().ok());
+
+    let Some(message) = q.message else {
+        bot.answer_callback_query(q.id).await?;
+        return Ok(());
+    };
+
+    if version != Some(TERMS_VERSION) {
+        bot.answer_callback_query(q.id)
+            .text("Условия изменились, ознакомьтесь с новой версией.")
+            .await?;
+        send_terms(&bot, message.chat.id).await?;
+        return Ok(());
+    }
+
+    db.set_consent(message.chat.id.0, TERMS_VERSION).await?;
+    bot.answer_callback_query(q.id).await?;
+    bot.edit_message_text(
+        message.chat.id,
+        message.id,
+        format!("{TERMS_TEXT}\n\nУсловия приняты. Теперь можно войти в аккаунт: /login."),
+    )
+    .await?;
+    Ok(())
+}
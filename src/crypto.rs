@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Key, XChaCha20Poly1305, XNonce,
+};
+use once_cell::sync::OnceCell;
+
+const NONCE_LEN: usize = 24;
+
+static KEYRING: OnceCell<Keyring> = OnceCell::new();
+
+struct Keyring {
+    current_version: i32,
+    ciphers: HashMap<i32, XChaCha20Poly1305>,
+}
+
+fn parse_key(name: &str, key: &str) -> Result<XChaCha20Poly1305> {
+    let key = BASE64
+        .decode(key.trim())
+        .with_context(|| format!("{name} is not valid base64"))?;
+    if key.len() != 32 {
+        bail!("{name} must be 32 bytes long, got {}", key.len());
+    }
+    Ok(XChaCha20Poly1305::new(Key::from_slice(&key)))
+}
+
+impl Keyring {
+    /// `old_keys` is in `TOKEN_OLD_KEYS` format.
+    fn new(current_version: i32, current_key: &str, old_keys: Option<&str>) -> Result<Self> {
+        let mut ciphers = HashMap::new();
+        ciphers.insert(current_version, parse_key("TOKEN_KEY", current_key)?);
+
+        for entry in old_keys
+            .unwrap_or_default()
+            .split(',')
+            .filter(|e| !e.trim().is_empty())
+        {
+            let (version, key) = entry
+                .split_once(':')
+                .context("TOKEN_OLD_KEYS entries must look like version:key")?;
+            let version: i32 = version
+                .trim()
+                .parse()
+                .context("TOKEN_OLD_KEYS version must be a number")?;
+            if version == current_version {
+                bail!("TOKEN_OLD_KEYS contains current key version {version}");
+            }
+            ciphers.insert(version, parse_key("TOKEN_OLD_KEYS", key)?);
+        }
+
+        Ok(Self {
+            current_version,
+            ciphers,
+        })
+    }
+
+    fn encrypt(&self, chat_id: i64, token: &str) -> Result<(i32, Vec<u8>)> {
+        let cipher = &self.ciphers[&self.current_version];
+
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: token.as_bytes(),
+                    aad: &chat_id.to_be_bytes(),
+                },
+            )
+            .map_err(|_| anyhow::anyhow!("failed to encrypt token"))?;
+
+        let mut out = nonce.to_vec();
+        out.extend(ciphertext);
+        Ok((self.current_version, out))
+    }
+
+    fn decrypt(&self, chat_id: i64, key_version: i32, data: &[u8]) -> Result<String> {
+        let Some(cipher) = self.ciphers.get(&key_version) else {
+            bail!("unknown token key version {key_version}");
+        };
+
+        if data.len() < NONCE_LEN {
+            bail!("encrypted token is too short");
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+
+        let plaintext = cipher
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &chat_id.to_be_bytes(),
+                },
+            )
+            .map_err(|_| anyhow::anyhow!("failed to decrypt token of {chat_id}"))?;
+
+        Ok(String::from_utf8(plaintext)?)
+    }
+}
+
+/// Loads token encryption keys. Must be called once before any token is read
+/// or written.
+///
+/// - `TOKEN_KEY`: current key (base64-encoded 32 bytes), used for writing
+/// - `TOKEN_KEY_VERSION`: version of the current key, 1 by default
+/// - `TOKEN_OLD_KEYS`: previous keys for reading, `version:key,version:key`
+pub fn init_from_env() -> Result<()> {
+    let current_version = match std::env::var("TOKEN_KEY_VERSION") {
+        Ok(v) => v
+            .trim()
+            .parse()
+            .context("TOKEN_KEY_VERSION must be a number")?,
+        Err(_) => 1,
+    };
+    let keyring = Keyring::new(
+        current_version,
+        &std::env::var("TOKEN_KEY").context("can't get TOKEN_KEY")?,
+        std::env::var("TOKEN_OLD_KEYS").ok().as_deref(),
+    )?;
+
+    if KEYRING.set(keyring).is_err() {
+        bail!("token keyring is already initialized");
+    }
+    Ok(())
+}
+
+fn keyring() -> &'static Keyring {
+    KEYRING.get().expect("token keyring must be initialized")
+}
+
+/// Version of the key new tokens are encrypted with.
+pub fn current_key_version() -> i32 {
+    keyring().current_version
+}
+
+/// Encrypts token with the current key, bound to chat id, so the ciphertext
+/// can't be moved to another row. Returns key version and `nonce || ciphertext`.
+pub fn encrypt_token(chat_id: i64, token: &str) -> Result<(i32, Vec<u8>)> {
+    keyring().encrypt(chat_id, token)
+}
+
+pub fn decrypt_token(chat_id: i64, key_version: i32, data: &[u8]) -> Result<String> {
+    keyring().decrypt(chat_id, key_version, data)
+}
+
+/// Fixed key for storage tests, can be called from every test.
+#[cfg(all(test, feature = "sqlite"))]
+pub fn init_for_tests() {
+    KEYRING.get_or_init(|| Keyring::new(1, &BASE64.encode([7; 32]), None).unwrap());
+}
+
+#[cfg(test)]
+mod tests;
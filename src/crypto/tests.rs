@@ -0,0 +1,126 @@
+use super::*;
+
+const CHAT_ID: i64 = 100;
+const TOKEN: &str = "matetech-token";
+
+fn key(byte: u8) -> String {
+    BASE64.encode([byte; 32])
+}
+
+fn keyring() -> Keyring {
+    Keyring::new(1, &key(1), None).unwrap()
+}
+
+#[test]
+fn round_trip() {
+    let keyring = keyring();
+    let (version, data) = keyring.encrypt(CHAT_ID, TOKEN).unwrap();
+
+    assert_eq!(version, 1);
+    assert_eq!(keyring.decrypt(CHAT_ID, version, &data).unwrap(), TOKEN);
+}
+
+#[test]
+fn nonce_is_random() {
+    let keyring = keyring();
+    let (_, first) = keyring.encrypt(CHAT_ID, TOKEN).unwrap();
+    let (_, second) = keyring.encrypt(CHAT_ID, TOKEN).unwrap();
+
+    assert_ne!(first, second);
+}
+
+#[test]
+fn ciphertext_is_bound_to_chat_id() {
+    let keyring = keyring();
+    let (version, data) = keyring.encrypt(CHAT_ID, TOKEN).unwrap();
+
+    assert!(keyring.decrypt(CHAT_ID + 1, version, &data).is_err());
+}
+
+#[test]
+fn tampered_ciphertext_is_rejected() {
+    let keyring = keyring();
+    let (version, mut data) = keyring.encrypt(CHAT_ID, TOKEN).unwrap();
+    *data.last_mut().unwrap() ^= 1;
+
+    assert!(keyring.decrypt(CHAT_ID, version, &data).is_err());
+}
+
+#[test]
+fn short_ciphertext_is_rejected() {
+    let keyring = keyring();
+
+    let err = keyring
+        .decrypt(CHAT_ID, 1, &[0; NONCE_LEN - 1])
+        .unwrap_err();
+    assert_eq!(err.to_string(), "encrypted token is too short");
+    // Nonce without ciphertext doesn't even have the authentication tag
+    assert!(keyring.decrypt(CHAT_ID, 1, &[0; NONCE_LEN]).is_err());
+}
+
+#[test]
+fn invalid_base64_key_is_rejected() {
+    let err = Keyring::new(1, "not base64!", None).err().unwrap();
+    assert_eq!(err.to_string(), "TOKEN_KEY is not valid base64");
+}
+
+#[test]
+fn short_key_is_rejected() {
+    let err = Keyring::new(1, &BASE64.encode([1; 16]), None)
+        .err()
+        .unwrap();
+    assert_eq!(err.to_string(), "TOKEN_KEY must be 32 bytes long, got 16");
+}
+
+#[test]
+fn old_key_version_still_decrypts() {
+    let (version, data) = keyring().encrypt(CHAT_ID, TOKEN).unwrap();
+    let rotated = Keyring::new(2, &key(2), Some(&format!("1:{}", key(1)))).unwrap();
+
+    assert_eq!(rotated.decrypt(CHAT_ID, version, &data).unwrap(), TOKEN);
+    let (version, data) = rotated.encrypt(CHAT_ID, TOKEN).unwrap();
+    assert_eq!(version, 2);
+    assert_eq!(rotated.decrypt(CHAT_ID, version, &data).unwrap(), TOKEN);
+}
+
+#[test]
+fn unknown_key_version_is_rejected() {
+    let (_, data) = keyring().encrypt(CHAT_ID, TOKEN).unwrap();
+    let rotated = Keyring::new(2, &key(2), None).unwrap();
+
+    let err = rotated.decrypt(CHAT_ID, 1, &data).unwrap_err();
+    assert_eq!(err.to_string(), "unknown token key version 1");
+}
+
+#[test]
+fn wrong_key_for_version_fails() {
+    let (version, data) = keyring().encrypt(CHAT_ID, TOKEN).unwrap();
+    let rotated = Keyring::new(2, &key(2), Some(&format!("1:{}", key(3)))).unwrap();
+
+    assert!(rotated.decrypt(CHAT_ID, version, &data).is_err());
+}
+
+#[test]
+fn malformed_old_keys_are_rejected() {
+    for (old_keys, message) in [
+        (key(1), "TOKEN_OLD_KEYS entries must look like version:key"),
+        (
+            format!("one:{}", key(1)),
+            "TOKEN_OLD_KEYS version must be a number",
+        ),
+        (
+            format!("2:{}", key(1)),
+            "TOKEN_OLD_KEYS contains current key version 2",
+        ),
+        ("1:short".to_owned(), "TOKEN_OLD_KEYS is not valid base64"),
+    ] {
+        let err = Keyring::new(2, &key(2), Some(&old_keys)).err().unwrap();
+        assert_eq!(err.to_string(), message, "{old_keys}");
+    }
+}
+
+#[test]
+fn empty_old_keys_entries_are_skipped() {
+    let keyring = Keyring::new(2, &key(2), Some(&format!(" ,1:{},", key(1)))).unwrap();
+    assert_eq!(keyring.ciphers.len(), 2);
+}
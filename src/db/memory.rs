@@ -0,0 +1,552 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+
+use super::*;
+use crate::engine::GeneratedAnswer;
+
+/// Storage that lives only until restart, for tests and quick local runs
+/// without PostgreSQL. Tokens are kept unencrypted.
+#[derive(Default)]
+pub struct MemoryStorage {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    tokens: HashMap<i64, Token>,
+    answers: HashMap<u32, GeneratedAnswer>,
+    /// State with the time of the last update
+    dialogues: HashMap<i64, (serde_json::Value, DateTime<Utc>)>,
+    consents: HashMap<i64, ConsentExport>,
+    /// In order of granting, with who granted
+    admins: Vec<(i64, Option<i64>)>,
+    /// In order of banning
+    bans: Vec<Ban>,
+    /// Broadcast with its status, id is the index + 1
+    broadcasts: Vec<(Broadcast, String)>,
+    /// Delivery by broadcast id and chat id
+    recipients: BTreeMap<(i64, i64), BroadcastDeliveryExport>,
+    users: HashMap<i64, ProfileExport>,
+    preferences: HashMap<i64, PreferencesExport>,
+    /// Chat id with the event, in order of recording
+    events: Vec<(i64, EventExport)>,
+}
+
+struct Token {
+    token: String,
+    status: TokenStatus,
+}
+
+impl MemoryStorage {
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+}
+
+fn count_by(items: impl Iterator<Item = String>) -> Vec<(String, i64)> {
+    let mut counts = HashMap::new();
+    for item in items {
+        *counts.entry(item).or_insert(0) += 1;
+    }
+    let mut counts: Vec<(String, i64)> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    counts
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn set_token(&self, chat_id: i64, token: &str) -> Result<()> {
+        let now = Utc::now();
+        self.state().tokens.insert(
+            chat_id,
+            Token {
+                token: token.to_owned(),
+                status: TokenStatus {
+                    created_at: now,
+                    last_used_at: None,
+                    last_validated_at: Some(now),
+                    invalid_since: None,
+                },
+            },
+        );
+        Ok(())
+    }
+
+    async fn reencrypt_tokens(&self, _batch_size: i64) -> Result<()> {
+        // tokens are not encrypted in memory
+        Ok(())
+    }
+
+    async fn save_answer(&self, ans: &GeneratedAnswer) -> Result<()> {
+        self.state().answers.insert(ans.question_id, ans.clone());
+        Ok(())
+    }
+
+    async fn get_token(&self, chat_id: i64) -> Result<Option<String>> {
+        let mut state = self.state();
+        match state.tokens.get_mut(&chat_id) {
+            Some(token) if token.status.invalid_since.is_none() => {
+                token.status.last_used_at = Some(Utc::now());
+                Ok(Some(token.token.clone()))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    async fn mark_token_valid(&self, chat_id: i64) -> Result<()> {
+        if let Some(token) = self.state().tokens.get_mut(&chat_id) {
+            token.status.last_validated_at = Some(Utc::now());
+        }
+        Ok(())
+    }
+
+    async fn mark_token_invalid(&self, chat_id: i64) -> Result<()> {
+        if let Some(token) = self.state().tokens.get_mut(&chat_id) {
+            token.status.invalid_since.get_or_insert_with(Utc::now);
+        }
+        Ok(())
+    }
+
+    async fn delete_token(&self, chat_id: i64) -> Result<bool> {
+        Ok(self.state().tokens.remove(&chat_id).is_some())
+    }
+
+    async fn purge_inactive_tokens(&self, days: i32) -> Result<u64> {
+        let threshold = Utc::now() - Duration::days(days.into());
+        let mut state = self.state();
+        let before = state.tokens.len();
+        state
+            .tokens
+            .retain(|_, t| t.status.last_used_at.unwrap_or(t.status.created_at) >= threshold);
+        Ok((before - state.tokens.len()) as u64)
+    }
+
+    async fn forget_user(&self, chat_id: i64) -> Result<ForgottenData> {
+        let mut state = self.state();
+
+        let recipients = state.recipients.len();
+        state.recipients.retain(|(_, id), _| *id != chat_id);
+        let events = state.events.len();
+        state.events.retain(|(id, _)| *id != chat_id);
+
+        Ok(ForgottenData {
+            token: state.tokens.remove(&chat_id).is_some(),
+            dialogue: state.dialogues.remove(&chat_id).is_some(),
+            consent: state.consents.remove(&chat_id).is_some(),
+            broadcast_deliveries: state.recipients.len() < recipients,
+            preferences: state.preferences.remove(&chat_id).is_some(),
+            profile: state.users.remove(&chat_id).is_some(),
+            events: state.events.len() < events,
+        })
+    }
+
+    async fn export_user(&self, chat_id: i64) -> Result<UserExport> {
+        let state = self.state();
+        Ok(UserExport {
+            chat_id,
+            profile: state.users.get(&chat_id).cloned(),
+            token: state.tokens.get(&chat_id).map(|t| TokenExport {
+                masked: mask_token(&t.token),
+                created_at: t.status.created_at,
+                last_used_at: t.status.last_used_at,
+                last_validated_at: t.status.last_validated_at,
+                invalid_since: t.status.invalid_since,
+            }),
+            dialogue: state.dialogues.get(&chat_id).map(|(d, _)| d.clone()),
+            consent: state.consents.get(&chat_id).cloned(),
+            preferences: state.preferences.get(&chat_id).cloned(),
+            broadcast_deliveries: state
+                .recipients
+                .iter()
+                .filter(|((_, id), _)| *id == chat_id)
+                .map(|(_, r)| r.clone())
+                .collect(),
+            events: state
+                .events
+                .iter()
+                .filter(|(id, _)| *id == chat_id)
+                .map(|(_, e)| e.clone())
+                .collect(),
+        })
+    }
+
+    async fn get_user_summary(&self, chat_id: i64, events_limit: i64) -> Result<UserSummary> {
+        let state = self.state();
+        Ok(UserSummary {
+            profile: state.users.get(&chat_id).cloned(),
+            token: state.tokens.get(&chat_id).map(|t| t.status.clone()),
+            consent: state.consents.get(&chat_id).cloned(),
+            ban: state.bans.iter().find(|b| b.chat_id == chat_id).cloned(),
+            broadcasts: state
+                .preferences
+                .get(&chat_id)
+                .map_or(true, |p| p.broadcasts),
+            recent_events: state
+                .events
+                .iter()
+                .rev()
+                .filter(|(id, _)| *id == chat_id)
+                .take(events_limit as usize)
+                .map(|(_, e)| e.clone())
+                .collect(),
+        })
+    }
+
+    async fn find_user_by_username(&self, username: &str) -> Result<Option<i64>> {
+        let username = username.to_lowercase();
+        Ok(self
+            .state()
+            .users
+            .iter()
+            .find(|(_, u)| u.username.as_deref().map(str::to_lowercase) == Some(username.clone()))
+            .map(|(chat_id, _)| *chat_id))
+    }
+
+    async fn set_consent(&self, chat_id: i64, terms_version: i32) -> Result<()> {
+        self.state().consents.insert(
+            chat_id,
+            ConsentExport {
+                terms_version,
+                accepted_at: Utc::now(),
+            },
+        );
+        Ok(())
+    }
+
+    async fn get_consent_version(&self, chat_id: i64) -> Result<Option<i32>> {
+        Ok(self.state().consents.get(&chat_id).map(|c| c.terms_version))
+    }
+
+    async fn add_admin(&self, chat_id: i64, granted_by: Option<i64>) -> Result<bool> {
+        let mut state = self.state();
+        if state.admins.iter().any(|(id, _)| *id == chat_id) {
+            return Ok(false);
+        }
+        state.admins.push((chat_id, granted_by));
+        Ok(true)
+    }
+
+    async fn remove_admin(&self, chat_id: i64) -> Result<bool> {
+        let mut state = self.state();
+        let before = state.admins.len();
+        state.admins.retain(|(id, _)| *id != chat_id);
+        Ok(state.admins.len() < before)
+    }
+
+    async fn is_admin(&self, chat_id: i64) -> Result<bool> {
+        Ok(self.state().admins.iter().any(|(id, _)| *id == chat_id))
+    }
+
+    async fn get_admins(&self) -> Result<Vec<i64>> {
+        Ok(self.state().admins.iter().map(|(id, _)| *id).collect())
+    }
+
+    async fn add_ban(&self, chat_id: i64, reason: Option<&str>, banned_by: i64) -> Result<bool> {
+        let mut state = self.state();
+        if state.bans.iter().any(|b| b.chat_id == chat_id) {
+            return Ok(false);
+        }
+        state.bans.push(Ban {
+            chat_id,
+            reason: reason.map(str::to_owned),
+            banned_by,
+            banned_at: Utc::now(),
+        });
+        Ok(true)
+    }
+
+    async fn remove_ban(&self, chat_id: i64) -> Result<bool> {
+        let mut state = self.state();
+        let before = state.bans.len();
+        state.bans.retain(|b| b.chat_id != chat_id);
+        Ok(state.bans.len() < before)
+    }
+
+    async fn get_ban(&self, chat_id: i64) -> Result<Option<Ban>> {
+        Ok(self
+            .state()
+            .bans
+            .iter()
+            .find(|b| b.chat_id == chat_id)
+            .cloned())
+    }
+
+    async fn get_bans(&self) -> Result<Vec<Ban>> {
+        Ok(self.state().bans.clone())
+    }
+
+    async fn create_broadcast(&self, broadcast: NewBroadcast<'_>) -> Result<i64> {
+        let mut state = self.state();
+        let id = state.broadcasts.len() as i64 + 1;
+        state.broadcasts.push((
+            Broadcast {
+                id,
+                author_chat_id: broadcast.author_chat_id,
+                text: broadcast.text.to_owned(),
+                parse_mode: broadcast.parse_mode.map(str::to_owned),
+                attachment_kind: broadcast.attachment_kind.map(str::to_owned),
+                attachment_file_id: broadcast.attachment_file_id.map(str::to_owned),
+                critical: broadcast.critical,
+                progress_message_id: None,
+            },
+            "draft".to_owned(),
+        ));
+        Ok(id)
+    }
+
+    async fn queue_broadcast(
+        &self,
+        id: i64,
+        progress_message_id: i32,
+        recipients: &[i64],
+    ) -> Result<bool> {
+        let mut state = self.state();
+        match state.broadcasts.get_mut((id - 1) as usize) {
+            Some((broadcast, status)) if status == "draft" => {
+                broadcast.progress_message_id = Some(progress_message_id);
+                *status = "queued".to_owned();
+            }
+            _ => return Ok(false),
+        }
+        for chat_id in recipients {
+            state.recipients.insert(
+                (id, *chat_id),
+                BroadcastDeliveryExport {
+                    broadcast_id: id,
+                    status: "pending".to_owned(),
+                    error: None,
+                    updated_at: None,
+                },
+            );
+        }
+        Ok(true)
+    }
+
+    async fn cancel_broadcast(&self, id: i64) -> Result<bool> {
+        match self.state().broadcasts.get_mut((id - 1) as usize) {
+            Some((_, status)) if status == "draft" => {
+                *status = "cancelled".to_owned();
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn get_broadcast(&self, id: i64) -> Result<Option<Broadcast>> {
+        Ok(self
+            .state()
+            .broadcasts
+            .get((id - 1) as usize)
+            .map(|(b, _)| b.clone()))
+    }
+
+    async fn next_broadcast(&self) -> Result<Option<Broadcast>> {
+        Ok(self
+            .state()
+            .broadcasts
+            .iter()
+            .find(|(_, status)| status == "queued" || status == "running")
+            .map(|(b, _)| b.clone()))
+    }
+
+    async fn set_broadcast_status(&self, id: i64, status: &str) -> Result<()> {
+        if let Some((_, s)) = self.state().broadcasts.get_mut((id - 1) as usize) {
+            *s = status.to_owned();
+        }
+        Ok(())
+    }
+
+    async fn get_pending_recipients(&self, broadcast_id: i64, limit: i64) -> Result<Vec<i64>> {
+        Ok(self
+            .state()
+            .recipients
+            .range((broadcast_id, i64::MIN)..=(broadcast_id, i64::MAX))
+            .filter(|(_, r)| r.status == "pending")
+            .take(limit as usize)
+            .map(|((_, chat_id), _)| *chat_id)
+            .collect())
+    }
+
+    async fn set_recipient_status(
+        &self,
+        broadcast_id: i64,
+        chat_id: i64,
+        status: &str,
+        error: Option<&str>,
+    ) -> Result<()> {
+        if let Some(recipient) = self.state().recipients.get_mut(&(broadcast_id, chat_id)) {
+            recipient.status = status.to_owned();
+            recipient.error = error.map(str::to_owned);
+            recipient.updated_at = Some(Utc::now());
+        }
+        Ok(())
+    }
+
+    async fn get_broadcast_counts(&self, broadcast_id: i64) -> Result<BroadcastCounts> {
+        let mut counts = BroadcastCounts::default();
+        for recipient in self
+            .state()
+            .recipients
+            .range((broadcast_id, i64::MIN)..=(broadcast_id, i64::MAX))
+            .map(|(_, r)| r)
+        {
+            counts.total += 1;
+            match recipient.status.as_str() {
+                "pending" => counts.pending += 1,
+                "delivered" => counts.delivered += 1,
+                "blocked" => counts.blocked += 1,
+                "failed" => counts.failed += 1,
+                _ => {}
+            }
+        }
+        Ok(counts)
+    }
+
+    async fn upsert_user(&self, profile: UserProfile<'_>) -> Result<()> {
+        let now = Utc::now();
+        let mut state = self.state();
+        let user = state
+            .users
+            .entry(profile.chat_id)
+            .or_insert_with(|| ProfileExport {
+                username: None,
+                first_name: None,
+                last_name: None,
+                language_code: None,
+                first_seen: now,
+                last_seen: now,
+                inactive_since: None,
+                inactive_reason: None,
+            });
+        user.username = profile.username.map(str::to_owned);
+        user.first_name = profile.first_name.map(str::to_owned);
+        user.last_name = profile.last_name.map(str::to_owned);
+        if let Some(language_code) = profile.language_code {
+            user.language_code = Some(language_code.to_owned());
+        }
+        user.last_seen = now;
+        user.inactive_since = None;
+        user.inactive_reason = None;
+        Ok(())
+    }
+
+    async fn mark_user_inactive(&self, chat_id: i64, reason: &str) -> Result<()> {
+        if let Some(user) = self.state().users.get_mut(&chat_id) {
+            if user.inactive_since.is_none() {
+                user.inactive_since = Some(Utc::now());
+                user.inactive_reason = Some(reason.to_owned());
+            }
+        }
+        Ok(())
+    }
+
+    async fn get_broadcast_recipients(&self, critical: bool) -> Result<Vec<i64>> {
+        let state = self.state();
+        let mut recipients: Vec<i64> = state
+            .users
+            .iter()
+            .filter(|(chat_id, user)| {
+                user.inactive_since.is_none()
+                    && (critical
+                        || state
+                            .preferences
+                            .get(chat_id)
+                            .map_or(true, |p| p.broadcasts))
+                    && !state.bans.iter().any(|b| b.chat_id == **chat_id)
+            })
+            .map(|(chat_id, _)| *chat_id)
+            .collect();
+        recipients.sort();
+        Ok(recipients)
+    }
+
+    async fn set_broadcasts_enabled(&self, chat_id: i64, enabled: bool) -> Result<bool> {
+        let mut state = self.state();
+        if let Some(p) = state.preferences.get(&chat_id) {
+            if p.broadcasts == enabled {
+                return Ok(false);
+            }
+        }
+        state.preferences.insert(
+            chat_id,
+            PreferencesExport {
+                broadcasts: enabled,
+                updated_at: Utc::now(),
+            },
+        );
+        Ok(true)
+    }
+
+    async fn add_event(&self, chat_id: i64, command: &str, error: Option<&str>) -> Result<()> {
+        self.state().events.push((
+            chat_id,
+            EventExport {
+                command: command.to_owned(),
+                error: error.map(str::to_owned),
+                created_at: Utc::now(),
+            },
+        ));
+        Ok(())
+    }
+
+    async fn get_user_counts(&self, since: DateTime<Utc>) -> Result<UserCounts> {
+        let state = self.state();
+        let active_users = || state.users.values().filter(|u| u.inactive_since.is_none());
+        Ok(UserCounts {
+            total: active_users().count() as i64,
+            inactive: (state.users.len() - active_users().count()) as i64,
+            active: active_users().filter(|u| u.last_seen >= since).count() as i64,
+            new: active_users().filter(|u| u.first_seen >= since).count() as i64,
+        })
+    }
+
+    async fn get_command_counts(&self, since: DateTime<Utc>) -> Result<Vec<(String, i64)>> {
+        Ok(count_by(
+            self.state()
+                .events
+                .iter()
+                .filter(|(_, e)| e.created_at >= since)
+                .map(|(_, e)| e.command.clone()),
+        ))
+    }
+
+    async fn get_error_counts(&self, since: DateTime<Utc>) -> Result<Vec<(String, i64)>> {
+        Ok(count_by(
+            self.state()
+                .events
+                .iter()
+                .filter(|(_, e)| e.created_at >= since)
+                .filter_map(|(_, e)| e.error.clone()),
+        ))
+    }
+
+    async fn get_dialogue(&self, chat_id: i64) -> Result<Option<serde_json::Value>> {
+        Ok(self.state().dialogues.get(&chat_id).map(|(d, _)| d.clone()))
+    }
+
+    async fn update_dialogue(&self, chat_id: i64, state: serde_json::Value) -> Result<()> {
+        self.state().dialogues.insert(chat_id, (state, Utc::now()));
+        Ok(())
+    }
+
+    async fn remove_dialogue(&self, chat_id: i64) -> Result<()> {
+        self.state().dialogues.remove(&chat_id);
+        Ok(())
+    }
+
+    async fn purge_stale_dialogues(&self, hours: i32) -> Result<u64> {
+        let threshold = Utc::now() - Duration::hours(hours.into());
+        let mut state = self.state();
+        let before = state.dialogues.len();
+        state
+            .dialogues
+            .retain(|_, (_, updated_at)| *updated_at >= threshold);
+        Ok((before - state.dialogues.len()) as u64)
+    }
+}
@@ -0,0 +1,378 @@
+use std::{future::Future, pin::Pin, sync::Arc};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{de::DeserializeOwned, Serialize};
+use sqlx::FromRow;
+use teloxide::types::ChatId;
+use tracing::*;
+
+use crate::engine::GeneratedAnswer;
+
+mod memory;
+mod postgres;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+pub use memory::MemoryStorage;
+pub use postgres::PgStorage;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteStorage;
+
+/// Storage shared between handlers, passed to dptree as a dependency.
+pub type Db = Arc<dyn Storage>;
+
+/// Opens the storage selected by `url`: `memory:` keeps everything in memory
+/// until restart, `sqlite:` opens a SQLite file (requires the `sqlite`
+/// feature), anything else is a PostgreSQL connection string.
+pub async fn connect(url: &str) -> Result<Db> {
+    if url.starts_with("memory:") {
+        warn!("using in-memory storage, all data will be lost on restart");
+        return Ok(Arc::new(MemoryStorage::default()));
+    }
+    if url.starts_with("sqlite:") {
+        #[cfg(feature = "sqlite")]
+        return Ok(Arc::new(SqliteStorage::connect(url).await?));
+        #[cfg(not(feature = "sqlite"))]
+        anyhow::bail!("SQLite support is not compiled in, build with `--features sqlite`");
+    }
+    Ok(Arc::new(PgStorage::connect(url).await?))
+}
+
+/// Every operation the bot does with its data.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn set_token(&self, chat_id: i64, token: &str) -> Result<()>;
+
+    /// Re-encrypts all tokens written with an old key under the current key,
+    /// `batch_size` rows per transaction.
+    async fn reencrypt_tokens(&self, batch_size: i64) -> Result<()>;
+
+    async fn save_answer(&self, ans: &GeneratedAnswer) -> Result<()>;
+
+    async fn get_token(&self, chat_id: i64) -> Result<Option<String>>;
+
+    /// Records that the token was accepted by Matetech.
+    async fn mark_token_valid(&self, chat_id: i64) -> Result<()>;
+
+    /// Records that Matetech rejected the token, [`Storage::get_token`] ignores
+    /// it since.
+    async fn mark_token_invalid(&self, chat_id: i64) -> Result<()>;
+
+    async fn delete_token(&self, chat_id: i64) -> Result<bool>;
+
+    /// Deletes tokens that haven't been used for `days` days. Returns the number
+    /// of deleted tokens.
+    async fn purge_inactive_tokens(&self, days: i32) -> Result<u64>;
+
+    /// Deletes every row tied to the chat.
+    async fn forget_user(&self, chat_id: i64) -> Result<ForgottenData>;
+
+    async fn export_user(&self, chat_id: i64) -> Result<UserExport>;
+
+    async fn get_user_summary(&self, chat_id: i64, events_limit: i64) -> Result<UserSummary>;
+
+    /// Username is matched case-insensitively, without `@`.
+    async fn find_user_by_username(&self, username: &str) -> Result<Option<i64>>;
+
+    async fn set_consent(&self, chat_id: i64, terms_version: i32) -> Result<()>;
+
+    /// Version of the terms the chat has accepted, if any.
+    async fn get_consent_version(&self, chat_id: i64) -> Result<Option<i32>>;
+
+    /// Returns `false` if the chat is already an admin.
+    async fn add_admin(&self, chat_id: i64, granted_by: Option<i64>) -> Result<bool>;
+
+    async fn remove_admin(&self, chat_id: i64) -> Result<bool>;
+
+    async fn is_admin(&self, chat_id: i64) -> Result<bool>;
+
+    async fn get_admins(&self) -> Result<Vec<i64>>;
+
+    /// Returns `false` if the chat is already banned.
+    async fn add_ban(&self, chat_id: i64, reason: Option<&str>, banned_by: i64) -> Result<bool>;
+
+    async fn remove_ban(&self, chat_id: i64) -> Result<bool>;
+
+    async fn get_ban(&self, chat_id: i64) -> Result<Option<Ban>>;
+
+    async fn get_bans(&self) -> Result<Vec<Ban>>;
+
+    /// Stores a broadcast draft waiting for confirmation. Returns broadcast id.
+    async fn create_broadcast(&self, broadcast: NewBroadcast<'_>) -> Result<i64>;
+
+    /// Queues confirmed draft to `recipients`. Returns `false` if the broadcast is
+    /// not a draft anymore.
+    async fn queue_broadcast(
+        &self,
+        id: i64,
+        progress_message_id: i32,
+        recipients: &[i64],
+    ) -> Result<bool>;
+
+    /// Returns `false` if the broadcast is not a draft anymore.
+    async fn cancel_broadcast(&self, id: i64) -> Result<bool>;
+
+    async fn get_broadcast(&self, id: i64) -> Result<Option<Broadcast>>;
+
+    /// Oldest broadcast that is not finished yet, including one interrupted by
+    /// restart.
+    async fn next_broadcast(&self) -> Result<Option<Broadcast>>;
+
+    async fn set_broadcast_status(&self, id: i64, status: &str) -> Result<()>;
+
+    async fn get_pending_recipients(&self, broadcast_id: i64, limit: i64) -> Result<Vec<i64>>;
+
+    async fn set_recipient_status(
+        &self,
+        broadcast_id: i64,
+        chat_id: i64,
+        status: &str,
+        error: Option<&str>,
+    ) -> Result<()>;
+
+    async fn get_broadcast_counts(&self, broadcast_id: i64) -> Result<BroadcastCounts>;
+
+    /// Creates the user or refreshes profile and `last_seen`, reactivating the
+    /// user.
+    async fn upsert_user(&self, profile: UserProfile<'_>) -> Result<()>;
+
+    /// Marks the user unreachable, e.g. after the bot was blocked.
+    async fn mark_user_inactive(&self, chat_id: i64, reason: &str) -> Result<()>;
+
+    /// Users that should receive a broadcast, skipping inactive and banned ones.
+    /// Critical broadcasts ignore `/unsubscribe`.
+    async fn get_broadcast_recipients(&self, critical: bool) -> Result<Vec<i64>>;
+
+    /// Returns `false` if the preference was already set to `enabled`.
+    async fn set_broadcasts_enabled(&self, chat_id: i64, enabled: bool) -> Result<bool>;
+
+    /// Records a handled command, `error` is [`crate::engine::MatetechError::kind`]
+    /// if the command failed.
+    async fn add_event(&self, chat_id: i64, command: &str, error: Option<&str>) -> Result<()>;
+
+    async fn get_user_counts(&self, since: DateTime<Utc>) -> Result<UserCounts>;
+
+    /// Number of handled commands per command since the given time, most used
+    /// first.
+    async fn get_command_counts(&self, since: DateTime<Utc>) -> Result<Vec<(String, i64)>>;
+
+    /// Number of commands per error kind since the given time, most frequent
+    /// first.
+    async fn get_error_counts(&self, since: DateTime<Utc>) -> Result<Vec<(String, i64)>>;
+
+    async fn get_dialogue(&self, chat_id: i64) -> Result<Option<serde_json::Value>>;
+
+    async fn update_dialogue(&self, chat_id: i64, state: serde_json::Value) -> Result<()>;
+
+    async fn remove_dialogue(&self, chat_id: i64) -> Result<()>;
+
+    /// Deletes dialogues not updated for `hours` hours, so an abandoned
+    /// `/login` doesn't keep the email. Returns the number of deleted
+    /// dialogues.
+    async fn purge_stale_dialogues(&self, hours: i32) -> Result<u64>;
+}
+
+/// What [`Storage::forget_user`] has deleted.
+#[derive(Debug, Default)]
+pub struct ForgottenData {
+    pub token: bool,
+    pub dialogue: bool,
+    pub consent: bool,
+    pub broadcast_deliveries: bool,
+    pub preferences: bool,
+    pub profile: bool,
+    pub events: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TokenExport {
+    /// Only the last characters of the token
+    pub masked: String,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub last_validated_at: Option<DateTime<Utc>>,
+    pub invalid_since: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct ConsentExport {
+    pub terms_version: i32,
+    pub accepted_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct ProfileExport {
+    pub username: Option<String>,
+    pub first_name: Option<String>,
+    pub last_name: Option<String>,
+    pub language_code: Option<String>,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub inactive_since: Option<DateTime<Utc>>,
+    pub inactive_reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct PreferencesExport {
+    pub broadcasts: bool,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct BroadcastDeliveryExport {
+    pub broadcast_id: i64,
+    /// `pending`, `delivered`, `blocked` or `failed`
+    pub status: String,
+    pub error: Option<String>,
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct EventExport {
+    pub command: String,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Everything stored about the chat, for `/mydata`.
+#[derive(Debug, Serialize)]
+pub struct UserExport {
+    pub chat_id: i64,
+    pub profile: Option<ProfileExport>,
+    pub token: Option<TokenExport>,
+    /// State of unfinished `/login` dialogue
+    pub dialogue: Option<serde_json::Value>,
+    pub consent: Option<ConsentExport>,
+    pub preferences: Option<PreferencesExport>,
+    /// Broadcasts sent to the chat, in order
+    pub broadcast_deliveries: Vec<BroadcastDeliveryExport>,
+    /// Handled commands, for statistics
+    pub events: Vec<EventExport>,
+}
+
+#[derive(Debug, Clone, FromRow)]
+pub struct TokenStatus {
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub last_validated_at: Option<DateTime<Utc>>,
+    pub invalid_since: Option<DateTime<Utc>>,
+}
+
+/// What admins see in `/user`. Never contains the token itself.
+#[derive(Debug)]
+pub struct UserSummary {
+    pub profile: Option<ProfileExport>,
+    pub token: Option<TokenStatus>,
+    pub consent: Option<ConsentExport>,
+    pub ban: Option<Ban>,
+    pub broadcasts: bool,
+    /// Most recent first
+    pub recent_events: Vec<EventExport>,
+}
+
+#[derive(Debug, Clone, FromRow)]
+pub struct Ban {
+    pub chat_id: i64,
+    pub reason: Option<String>,
+    pub banned_by: i64,
+    pub banned_at: DateTime<Utc>,
+}
+
+#[derive(Debug)]
+pub struct NewBroadcast<'a> {
+    pub author_chat_id: i64,
+    pub text: &'a str,
+    pub parse_mode: Option<&'a str>,
+    pub attachment_kind: Option<&'a str>,
+    pub attachment_file_id: Option<&'a str>,
+    /// Delivered to unsubscribed users too
+    pub critical: bool,
+}
+
+#[derive(Debug, Clone, FromRow)]
+pub struct Broadcast {
+    pub id: i64,
+    pub author_chat_id: i64,
+    pub text: String,
+    pub parse_mode: Option<String>,
+    pub attachment_kind: Option<String>,
+    pub attachment_file_id: Option<String>,
+    pub critical: bool,
+    pub progress_message_id: Option<i32>,
+}
+
+#[derive(Debug, Default, FromRow)]
+pub struct BroadcastCounts {
+    pub total: i64,
+    pub pending: i64,
+    pub delivered: i64,
+    pub blocked: i64,
+    pub failed: i64,
+}
+
+#[derive(Debug)]
+pub struct UserProfile<'a> {
+    pub chat_id: i64,
+    pub username: Option<&'a str>,
+    pub first_name: Option<&'a str>,
+    pub last_name: Option<&'a str>,
+    pub language_code: Option<&'a str>,
+}
+
+#[derive(Debug, FromRow)]
+pub struct UserCounts {
+    /// Users that did not block the bot
+    pub total: i64,
+    pub inactive: i64,
+    /// Users that wrote to the bot since the given time
+    pub active: i64,
+    /// Users that wrote to the bot for the first time since the given time
+    pub new: i64,
+}
+
+fn mask_token(token: &str) -> String {
+    let len = token.chars().count();
+    let tail: String = token.chars().skip(len.saturating_sub(4)).collect();
+    format!("***{tail}")
+}
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = anyhow::Result<T>> + Send>>;
+
+/// Dialogue storage on top of [`Storage`], so dialogues survive restarts.
+pub struct DialogueStorage {
+    db: Db,
+}
+
+impl DialogueStorage {
+    pub fn new(db: Db) -> Arc<Self> {
+        Arc::new(Self { db })
+    }
+}
+
+impl<D> teloxide::dispatching::dialogue::Storage<D> for DialogueStorage
+where
+    D: Serialize + DeserializeOwned + Send + 'static,
+{
+    type Error = anyhow::Error;
+
+    fn remove_dialogue(self: Arc<Self>, chat_id: ChatId) -> BoxFuture<()> {
+        Box::pin(async move { self.db.remove_dialogue(chat_id.0).await })
+    }
+
+    fn update_dialogue(self: Arc<Self>, chat_id: ChatId, dialogue: D) -> BoxFuture<()> {
+        Box::pin(async move {
+            self.db
+                .update_dialogue(chat_id.0, serde_json::to_value(dialogue)?)
+                .await
+        })
+    }
+
+    fn get_dialogue(self: Arc<Self>, chat_id: ChatId) -> BoxFuture<Option<D>> {
+        Box::pin(async move {
+            let state = self.db.get_dialogue(chat_id.0).await?;
+            Ok(state.map(serde_json::from_value).transpose()?)
+        })
+    }
+}
@@ -0,0 +1,915 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use tracing::*;
+
+use super::*;
+use crate::{crypto, engine::GeneratedAnswer};
+
+/// Production storage in PostgreSQL.
+pub struct PgStorage {
+    pool: PgPool,
+}
+
+impl PgStorage {
+    /// Connects and applies migrations.
+    pub async fn connect(url: &str) -> Result<Self> {
+        let pool = PgPool::connect(url).await?;
+        sqlx::migrate!().run(&pool).await?;
+        let db = Self { pool };
+        db.encrypt_plaintext_tokens().await?;
+        Ok(db)
+    }
+
+    /// Encrypts tokens left in plaintext `token` column by older versions.
+    async fn encrypt_plaintext_tokens(&self) -> Result<()> {
+        let rows = sqlx::query!(
+            r#"
+SELECT chat_id, token AS "token!"
+FROM tokens
+WHERE token IS NOT NULL
+            "#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        if rows.is_empty() {
+            return Ok(());
+        }
+
+        let mut tx = self.pool.begin().await?;
+        for row in &rows {
+            let (key_version, encrypted_token) = crypto::encrypt_token(row.chat_id, &row.token)?;
+            sqlx::query!(
+                r#"
+UPDATE tokens
+SET ( token, encrypted_token, key_version ) = ( NULL, $2, $3 )
+WHERE chat_id = $1
+                "#,
+                row.chat_id,
+                encrypted_token,
+                key_version,
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+
+        info!("encrypted {} plaintext tokens", rows.len());
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Storage for PgStorage {
+    async fn set_token(&self, chat_id: i64, token: &str) -> Result<()> {
+        let (key_version, encrypted_token) = crypto::encrypt_token(chat_id, token)?;
+        sqlx::query!(
+            r#"
+INSERT INTO tokens ( chat_id, token, encrypted_token, key_version, last_validated_at )
+    VALUES ( $1, NULL, $2, $3, now() )
+    ON CONFLICT ( chat_id ) DO UPDATE
+        SET (
+            token, encrypted_token, key_version,
+            created_at, last_used_at, last_validated_at, invalid_since
+        ) = ( NULL, $2, $3, now(), NULL, now(), NULL )
+            "#,
+            chat_id,
+            encrypted_token,
+            key_version,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn reencrypt_tokens(&self, batch_size: i64) -> Result<()> {
+        let current_version = crypto::current_key_version();
+
+        let total = sqlx::query_scalar!(
+            r#"
+SELECT COUNT(*) AS "count!"
+FROM tokens
+WHERE encrypted_token IS NOT NULL AND key_version <> $1
+            "#,
+            current_version
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        info!("{total} tokens to re-encrypt with key version {current_version}");
+
+        let mut done = 0;
+        loop {
+            let mut tx = self.pool.begin().await?;
+            let rows = sqlx::query!(
+                r#"
+SELECT chat_id, key_version, encrypted_token AS "encrypted_token!"
+FROM tokens
+WHERE encrypted_token IS NOT NULL AND key_version <> $1
+ORDER BY chat_id
+LIMIT $2
+FOR UPDATE
+                "#,
+                current_version,
+                batch_size,
+            )
+            .fetch_all(&mut *tx)
+            .await?;
+
+            if rows.is_empty() {
+                break;
+            }
+
+            for row in &rows {
+                let token =
+                    crypto::decrypt_token(row.chat_id, row.key_version, &row.encrypted_token)?;
+                let (key_version, encrypted_token) = crypto::encrypt_token(row.chat_id, &token)?;
+                sqlx::query!(
+                    r#"
+UPDATE tokens
+SET ( encrypted_token, key_version ) = ( $2, $3 )
+WHERE chat_id = $1
+                    "#,
+                    row.chat_id,
+                    encrypted_token,
+                    key_version,
+                )
+                .execute(&mut *tx)
+                .await?;
+            }
+            tx.commit().await?;
+
+            done += rows.len() as i64;
+            info!("re-encrypted {done}/{total} tokens");
+        }
+
+        info!("token key rotation finished");
+        Ok(())
+    }
+
+    async fn save_answer(&self, ans: &GeneratedAnswer) -> Result<()> {
+        sqlx::query!(
+            r#"
+INSERT INTO answers ( id, question, human, exact, machine )
+    VALUES ( $1, $2, $3, $4, $5 )
+    ON CONFLICT ( id ) DO UPDATE
+        SET ( question, human, exact, machine ) = ( $2, $3, $4, $5 )
+            "#,
+            ans.question_id as i32,
+            ans.question,
+            ans.human,
+            ans.exact,
+            ans.machine,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_token(&self, chat_id: i64) -> Result<Option<String>> {
+        let token = sqlx::query!(
+            r#"
+UPDATE tokens
+SET last_used_at = now()
+WHERE chat_id = $1 AND encrypted_token IS NOT NULL AND invalid_since IS NULL
+RETURNING key_version, encrypted_token AS "encrypted_token!"
+            "#,
+            chat_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        token
+            .map(|r| crypto::decrypt_token(chat_id, r.key_version, &r.encrypted_token))
+            .transpose()
+    }
+
+    async fn mark_token_valid(&self, chat_id: i64) -> Result<()> {
+        sqlx::query!(
+            "UPDATE tokens SET last_validated_at = now() WHERE chat_id = $1",
+            chat_id
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn mark_token_invalid(&self, chat_id: i64) -> Result<()> {
+        sqlx::query!(
+            r#"
+UPDATE tokens
+SET invalid_since = now()
+WHERE chat_id = $1 AND invalid_since IS NULL
+            "#,
+            chat_id
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn delete_token(&self, chat_id: i64) -> Result<bool> {
+        let result = sqlx::query!("DELETE FROM tokens WHERE chat_id = $1", chat_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn purge_inactive_tokens(&self, days: i32) -> Result<u64> {
+        let result = sqlx::query!(
+            r#"
+DELETE FROM tokens
+WHERE COALESCE(last_used_at, created_at) < now() - make_interval(days => $1)
+            "#,
+            days
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn forget_user(&self, chat_id: i64) -> Result<ForgottenData> {
+        let mut tx = self.pool.begin().await?;
+
+        let token = sqlx::query!("DELETE FROM tokens WHERE chat_id = $1", chat_id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected()
+            > 0;
+        let dialogue = sqlx::query!("DELETE FROM dialogues WHERE chat_id = $1", chat_id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected()
+            > 0;
+        let consent = sqlx::query!("DELETE FROM consents WHERE chat_id = $1", chat_id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected()
+            > 0;
+        let broadcast_deliveries = sqlx::query!(
+            "DELETE FROM broadcast_recipients WHERE chat_id = $1",
+            chat_id
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected()
+            > 0;
+        let preferences = sqlx::query!("DELETE FROM preferences WHERE chat_id = $1", chat_id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected()
+            > 0;
+        let profile = sqlx::query!("DELETE FROM users WHERE chat_id = $1", chat_id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected()
+            > 0;
+        let events = sqlx::query!("DELETE FROM events WHERE chat_id = $1", chat_id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected()
+            > 0;
+
+        tx.commit().await?;
+        Ok(ForgottenData {
+            token,
+            dialogue,
+            consent,
+            broadcast_deliveries,
+            preferences,
+            profile,
+            events,
+        })
+    }
+
+    async fn export_user(&self, chat_id: i64) -> Result<UserExport> {
+        let profile = sqlx::query_as!(
+            ProfileExport,
+            r#"
+SELECT
+    username, first_name, last_name, language_code, first_seen, last_seen,
+    inactive_since, inactive_reason
+FROM users
+WHERE chat_id = $1
+            "#,
+            chat_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        let token = sqlx::query!(
+            r#"
+SELECT
+    key_version, encrypted_token AS "encrypted_token!",
+    created_at, last_used_at, last_validated_at, invalid_since
+FROM tokens
+WHERE chat_id = $1 AND encrypted_token IS NOT NULL
+            "#,
+            chat_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        let token = match token {
+            Some(r) => Some(TokenExport {
+                masked: mask_token(&crypto::decrypt_token(
+                    chat_id,
+                    r.key_version,
+                    &r.encrypted_token,
+                )?),
+                created_at: r.created_at,
+                last_used_at: r.last_used_at,
+                last_validated_at: r.last_validated_at,
+                invalid_since: r.invalid_since,
+            }),
+            None => None,
+        };
+
+        let dialogue =
+            sqlx::query_scalar!("SELECT state FROM dialogues WHERE chat_id = $1", chat_id)
+                .fetch_optional(&self.pool)
+                .await?;
+
+        let consent = sqlx::query_as!(
+            ConsentExport,
+            "SELECT terms_version, accepted_at FROM consents WHERE chat_id = $1",
+            chat_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        let preferences = sqlx::query_as!(
+            PreferencesExport,
+            "SELECT broadcasts, updated_at FROM preferences WHERE chat_id = $1",
+            chat_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        let broadcast_deliveries = sqlx::query_as!(
+            BroadcastDeliveryExport,
+            r#"
+SELECT broadcast_id, status, error, updated_at
+FROM broadcast_recipients
+WHERE chat_id = $1
+ORDER BY broadcast_id
+            "#,
+            chat_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let events = sqlx::query_as!(
+            EventExport,
+            "SELECT command, error, created_at FROM events WHERE chat_id = $1 ORDER BY id",
+            chat_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(UserExport {
+            chat_id,
+            profile,
+            token,
+            dialogue,
+            consent,
+            preferences,
+            broadcast_deliveries,
+            events,
+        })
+    }
+
+    async fn get_user_summary(&self, chat_id: i64, events_limit: i64) -> Result<UserSummary> {
+        let profile = sqlx::query_as!(
+            ProfileExport,
+            r#"
+SELECT
+    username, first_name, last_name, language_code, first_seen, last_seen,
+    inactive_since, inactive_reason
+FROM users
+WHERE chat_id = $1
+            "#,
+            chat_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        let token = sqlx::query_as!(
+            TokenStatus,
+            r#"
+SELECT created_at, last_used_at, last_validated_at, invalid_since
+FROM tokens
+WHERE chat_id = $1 AND encrypted_token IS NOT NULL
+            "#,
+            chat_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        let consent = sqlx::query_as!(
+            ConsentExport,
+            "SELECT terms_version, accepted_at FROM consents WHERE chat_id = $1",
+            chat_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        let broadcasts = sqlx::query_scalar!(
+            "SELECT broadcasts FROM preferences WHERE chat_id = $1",
+            chat_id
+        )
+        .fetch_optional(&self.pool)
+        .await?
+        .unwrap_or(true);
+
+        let recent_events = sqlx::query_as!(
+            EventExport,
+            r#"
+SELECT command, error, created_at
+FROM events
+WHERE chat_id = $1
+ORDER BY id DESC
+LIMIT $2
+            "#,
+            chat_id,
+            events_limit
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(UserSummary {
+            profile,
+            token,
+            consent,
+            ban: self.get_ban(chat_id).await?,
+            broadcasts,
+            recent_events,
+        })
+    }
+
+    async fn find_user_by_username(&self, username: &str) -> Result<Option<i64>> {
+        Ok(sqlx::query_scalar!(
+            "SELECT chat_id FROM users WHERE lower(username) = lower($1)",
+            username
+        )
+        .fetch_optional(&self.pool)
+        .await?)
+    }
+
+    async fn set_consent(&self, chat_id: i64, terms_version: i32) -> Result<()> {
+        sqlx::query!(
+            r#"
+INSERT INTO consents ( chat_id, terms_version )
+    VALUES ( $1, $2 )
+    ON CONFLICT ( chat_id ) DO UPDATE
+        SET ( terms_version, accepted_at ) = ( $2, now() )
+            "#,
+            chat_id,
+            terms_version,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_consent_version(&self, chat_id: i64) -> Result<Option<i32>> {
+        Ok(sqlx::query_scalar!(
+            "SELECT terms_version FROM consents WHERE chat_id = $1",
+            chat_id
+        )
+        .fetch_optional(&self.pool)
+        .await?)
+    }
+
+    async fn add_admin(&self, chat_id: i64, granted_by: Option<i64>) -> Result<bool> {
+        let result = sqlx::query!(
+            r#"
+INSERT INTO admins ( chat_id, granted_by )
+    VALUES ( $1, $2 )
+    ON CONFLICT ( chat_id ) DO NOTHING
+            "#,
+            chat_id,
+            granted_by,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn remove_admin(&self, chat_id: i64) -> Result<bool> {
+        let result = sqlx::query!("DELETE FROM admins WHERE chat_id = $1", chat_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn is_admin(&self, chat_id: i64) -> Result<bool> {
+        Ok(sqlx::query_scalar!(
+            r#"SELECT EXISTS ( SELECT 1 FROM admins WHERE chat_id = $1 ) AS "exists!""#,
+            chat_id
+        )
+        .fetch_one(&self.pool)
+        .await?)
+    }
+
+    async fn get_admins(&self) -> Result<Vec<i64>> {
+        Ok(
+            sqlx::query_scalar!("SELECT chat_id FROM admins ORDER BY granted_at")
+                .fetch_all(&self.pool)
+                .await?,
+        )
+    }
+
+    async fn add_ban(&self, chat_id: i64, reason: Option<&str>, banned_by: i64) -> Result<bool> {
+        let result = sqlx::query!(
+            r#"
+INSERT INTO bans ( chat_id, reason, banned_by )
+    VALUES ( $1, $2, $3 )
+    ON CONFLICT ( chat_id ) DO NOTHING
+            "#,
+            chat_id,
+            reason,
+            banned_by,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn remove_ban(&self, chat_id: i64) -> Result<bool> {
+        let result = sqlx::query!("DELETE FROM bans WHERE chat_id = $1", chat_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn get_ban(&self, chat_id: i64) -> Result<Option<Ban>> {
+        Ok(sqlx::query_as!(
+            Ban,
+            "SELECT chat_id, reason, banned_by, banned_at FROM bans WHERE chat_id = $1",
+            chat_id
+        )
+        .fetch_optional(&self.pool)
+        .await?)
+    }
+
+    async fn get_bans(&self) -> Result<Vec<Ban>> {
+        Ok(sqlx::query_as!(
+            Ban,
+            "SELECT chat_id, reason, banned_by, banned_at FROM bans ORDER BY banned_at"
+        )
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    async fn create_broadcast(&self, broadcast: NewBroadcast<'_>) -> Result<i64> {
+        Ok(sqlx::query_scalar!(
+            r#"
+INSERT INTO broadcasts (
+    author_chat_id, text, parse_mode, attachment_kind, attachment_file_id, critical
+)
+    VALUES ( $1, $2, $3, $4, $5, $6 )
+    RETURNING id
+            "#,
+            broadcast.author_chat_id,
+            broadcast.text,
+            broadcast.parse_mode,
+            broadcast.attachment_kind,
+            broadcast.attachment_file_id,
+            broadcast.critical,
+        )
+        .fetch_one(&self.pool)
+        .await?)
+    }
+
+    async fn queue_broadcast(
+        &self,
+        id: i64,
+        progress_message_id: i32,
+        recipients: &[i64],
+    ) -> Result<bool> {
+        let mut tx = self.pool.begin().await?;
+
+        let queued = sqlx::query!(
+            r#"
+UPDATE broadcasts
+SET ( status, progress_message_id ) = ( 'queued', $2 )
+WHERE id = $1 AND status = 'draft'
+            "#,
+            id,
+            progress_message_id,
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected()
+            > 0;
+        if !queued {
+            return Ok(false);
+        }
+
+        sqlx::query!(
+            r#"
+INSERT INTO broadcast_recipients ( broadcast_id, chat_id )
+    SELECT $1, * FROM UNNEST($2::BIGINT[])
+            "#,
+            id,
+            recipients,
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    async fn cancel_broadcast(&self, id: i64) -> Result<bool> {
+        let result = sqlx::query!(
+            "UPDATE broadcasts SET status = 'cancelled' WHERE id = $1 AND status = 'draft'",
+            id
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn get_broadcast(&self, id: i64) -> Result<Option<Broadcast>> {
+        Ok(sqlx::query_as!(
+            Broadcast,
+            r#"
+SELECT
+    id, author_chat_id, text, parse_mode,
+    attachment_kind, attachment_file_id, critical, progress_message_id
+FROM broadcasts
+WHERE id = $1
+            "#,
+            id
+        )
+        .fetch_optional(&self.pool)
+        .await?)
+    }
+
+    async fn next_broadcast(&self) -> Result<Option<Broadcast>> {
+        Ok(sqlx::query_as!(
+            Broadcast,
+            r#"
+SELECT
+    id, author_chat_id, text, parse_mode,
+    attachment_kind, attachment_file_id, critical, progress_message_id
+FROM broadcasts
+WHERE status IN ( 'queued', 'running' )
+ORDER BY id
+LIMIT 1
+            "#
+        )
+        .fetch_optional(&self.pool)
+        .await?)
+    }
+
+    async fn set_broadcast_status(&self, id: i64, status: &str) -> Result<()> {
+        sqlx::query!(
+            r#"
+UPDATE broadcasts
+SET
+    status = $2,
+    finished_at = CASE WHEN $2 = 'finished' THEN now() END
+WHERE id = $1
+            "#,
+            id,
+            status
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_pending_recipients(&self, broadcast_id: i64, limit: i64) -> Result<Vec<i64>> {
+        Ok(sqlx::query_scalar!(
+            r#"
+SELECT chat_id
+FROM broadcast_recipients
+WHERE broadcast_id = $1 AND status = 'pending'
+ORDER BY chat_id
+LIMIT $2
+            "#,
+            broadcast_id,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    async fn set_recipient_status(
+        &self,
+        broadcast_id: i64,
+        chat_id: i64,
+        status: &str,
+        error: Option<&str>,
+    ) -> Result<()> {
+        sqlx::query!(
+            r#"
+UPDATE broadcast_recipients
+SET ( status, error, updated_at ) = ( $3, $4, now() )
+WHERE broadcast_id = $1 AND chat_id = $2
+            "#,
+            broadcast_id,
+            chat_id,
+            status,
+            error,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_broadcast_counts(&self, broadcast_id: i64) -> Result<BroadcastCounts> {
+        Ok(sqlx::query_as!(
+            BroadcastCounts,
+            r#"
+SELECT
+    COUNT(*) AS "total!",
+    COUNT(*) FILTER ( WHERE status = 'pending' ) AS "pending!",
+    COUNT(*) FILTER ( WHERE status = 'delivered' ) AS "delivered!",
+    COUNT(*) FILTER ( WHERE status = 'blocked' ) AS "blocked!",
+    COUNT(*) FILTER ( WHERE status = 'failed' ) AS "failed!"
+FROM broadcast_recipients
+WHERE broadcast_id = $1
+            "#,
+            broadcast_id
+        )
+        .fetch_one(&self.pool)
+        .await?)
+    }
+
+    async fn upsert_user(&self, profile: UserProfile<'_>) -> Result<()> {
+        sqlx::query!(
+            r#"
+INSERT INTO users ( chat_id, username, first_name, last_name, language_code )
+    VALUES ( $1, $2, $3, $4, $5 )
+    ON CONFLICT ( chat_id ) DO UPDATE
+    SET (
+        username, first_name, last_name, language_code, last_seen,
+        inactive_since, inactive_reason
+    ) = ( $2, $3, $4, COALESCE($5, users.language_code), now(), NULL, NULL )
+            "#,
+            profile.chat_id,
+            profile.username,
+            profile.first_name,
+            profile.last_name,
+            profile.language_code,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn mark_user_inactive(&self, chat_id: i64, reason: &str) -> Result<()> {
+        sqlx::query!(
+            r#"
+UPDATE users
+SET ( inactive_since, inactive_reason ) = ( now(), $2 )
+WHERE chat_id = $1 AND inactive_since IS NULL
+            "#,
+            chat_id,
+            reason
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_broadcast_recipients(&self, critical: bool) -> Result<Vec<i64>> {
+        let users = sqlx::query!(
+            r#"
+SELECT chat_id AS "chat_id!"
+FROM users LEFT JOIN preferences USING ( chat_id )
+WHERE
+    inactive_since IS NULL
+    AND ( $1 OR COALESCE(preferences.broadcasts, TRUE) )
+    AND NOT EXISTS ( SELECT 1 FROM bans WHERE bans.chat_id = users.chat_id )
+            "#,
+            critical
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(users.iter().map(|r| r.chat_id).collect())
+    }
+
+    async fn set_broadcasts_enabled(&self, chat_id: i64, enabled: bool) -> Result<bool> {
+        let result = sqlx::query!(
+            r#"
+INSERT INTO preferences ( chat_id, broadcasts )
+    VALUES ( $1, $2 )
+    ON CONFLICT ( chat_id ) DO UPDATE
+    SET ( broadcasts, updated_at ) = ( $2, now() )
+    WHERE preferences.broadcasts <> $2
+            "#,
+            chat_id,
+            enabled
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn add_event(&self, chat_id: i64, command: &str, error: Option<&str>) -> Result<()> {
+        sqlx::query!(
+            "INSERT INTO events ( chat_id, command, error ) VALUES ( $1, $2, $3 )",
+            chat_id,
+            command,
+            error
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_user_counts(&self, since: DateTime<Utc>) -> Result<UserCounts> {
+        Ok(sqlx::query_as!(
+            UserCounts,
+            r#"
+SELECT
+    COUNT(*) FILTER ( WHERE inactive_since IS NULL ) AS "total!",
+    COUNT(*) FILTER ( WHERE inactive_since IS NOT NULL ) AS "inactive!",
+    COUNT(*) FILTER ( WHERE inactive_since IS NULL AND last_seen >= $1 ) AS "active!",
+    COUNT(*) FILTER ( WHERE inactive_since IS NULL AND first_seen >= $1 ) AS "new!"
+FROM users
+            "#,
+            since
+        )
+        .fetch_one(&self.pool)
+        .await?)
+    }
+
+    async fn get_command_counts(&self, since: DateTime<Utc>) -> Result<Vec<(String, i64)>> {
+        let counts = sqlx::query!(
+            r#"
+SELECT command, COUNT(*) AS "count!"
+FROM events
+WHERE created_at >= $1
+GROUP BY command
+ORDER BY 2 DESC
+            "#,
+            since
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(counts.into_iter().map(|r| (r.command, r.count)).collect())
+    }
+
+    async fn get_error_counts(&self, since: DateTime<Utc>) -> Result<Vec<(String, i64)>> {
+        let counts = sqlx::query!(
+            r#"
+SELECT error AS "error!", COUNT(*) AS "count!"
+FROM events
+WHERE created_at >= $1 AND error IS NOT NULL
+GROUP BY error
+ORDER BY 2 DESC
+            "#,
+            since
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(counts.into_iter().map(|r| (r.error, r.count)).collect())
+    }
+
+    async fn get_dialogue(&self, chat_id: i64) -> Result<Option<serde_json::Value>> {
+        Ok(
+            sqlx::query_scalar!("SELECT state FROM dialogues WHERE chat_id = $1", chat_id)
+                .fetch_optional(&self.pool)
+                .await?,
+        )
+    }
+
+    async fn update_dialogue(&self, chat_id: i64, state: serde_json::Value) -> Result<()> {
+        sqlx::query!(
+            r#"
+INSERT INTO dialogues ( chat_id, state )
+    VALUES ( $1, $2 )
+    ON CONFLICT ( chat_id ) DO UPDATE
+        SET ( state, updated_at ) = ( $2, now() )
+            "#,
+            chat_id,
+            state,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn remove_dialogue(&self, chat_id: i64) -> Result<()> {
+        sqlx::query!("DELETE FROM dialogues WHERE chat_id = $1", chat_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn purge_stale_dialogues(&self, hours: i32) -> Result<u64> {
+        let result = sqlx::query!(
+            "DELETE FROM dialogues WHERE updated_at < now() - make_interval(hours => $1)",
+            hours
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+}
@@ -0,0 +1,292 @@
+use sqlx::sqlite::SqlitePoolOptions;
+
+use super::*;
+
+const CHAT_ID: i64 = 100;
+const OTHER_CHAT_ID: i64 = 200;
+
+/// Fresh in-memory database. sqlx names every parsed `sqlite::memory:` URL
+/// uniquely, so tests don't share data.
+async fn storage() -> SqliteStorage {
+    crypto::init_for_tests();
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    sqlx::migrate!("./migrations/sqlite")
+        .run(&pool)
+        .await
+        .unwrap();
+    SqliteStorage { pool }
+}
+
+fn profile(chat_id: i64, username: &str) -> UserProfile<'_> {
+    UserProfile {
+        chat_id,
+        username: Some(username),
+        first_name: Some("Test"),
+        last_name: None,
+        language_code: Some("ru"),
+    }
+}
+
+#[tokio::test]
+async fn tokens() {
+    let db = storage().await;
+    assert_eq!(db.get_token(CHAT_ID).await.unwrap(), None);
+
+    db.set_token(CHAT_ID, "first").await.unwrap();
+    db.set_token(CHAT_ID, "second").await.unwrap();
+    assert_eq!(
+        db.get_token(CHAT_ID).await.unwrap().as_deref(),
+        Some("second")
+    );
+
+    db.mark_token_valid(CHAT_ID).await.unwrap();
+    db.mark_token_invalid(CHAT_ID).await.unwrap();
+    assert_eq!(db.get_token(CHAT_ID).await.unwrap(), None);
+    let summary = db.get_user_summary(CHAT_ID, 10).await.unwrap();
+    assert!(summary.token.unwrap().invalid_since.is_some());
+
+    // Logging in again makes the token valid
+    db.set_token(CHAT_ID, "third").await.unwrap();
+    assert_eq!(
+        db.get_token(CHAT_ID).await.unwrap().as_deref(),
+        Some("third")
+    );
+    assert_eq!(
+        db.export_user(CHAT_ID).await.unwrap().token.unwrap().masked,
+        "***hird"
+    );
+
+    assert!(db.delete_token(CHAT_ID).await.unwrap());
+    assert!(!db.delete_token(CHAT_ID).await.unwrap());
+}
+
+#[tokio::test]
+async fn purge_keeps_recently_used_tokens() {
+    let db = storage().await;
+    db.set_token(CHAT_ID, "token").await.unwrap();
+    db.get_token(CHAT_ID).await.unwrap();
+
+    assert_eq!(db.purge_inactive_tokens(1).await.unwrap(), 0);
+    assert!(db.get_token(CHAT_ID).await.unwrap().is_some());
+}
+
+#[tokio::test]
+async fn reencrypt_tokens() {
+    let db = storage().await;
+    db.set_token(CHAT_ID, "token").await.unwrap();
+
+    // Already under the current key
+    db.reencrypt_tokens(10).await.unwrap();
+    assert_eq!(
+        db.get_token(CHAT_ID).await.unwrap().as_deref(),
+        Some("token")
+    );
+
+    sqlx::query("UPDATE tokens SET key_version = 0")
+        .execute(&db.pool)
+        .await
+        .unwrap();
+    let err = db.reencrypt_tokens(10).await.unwrap_err();
+    assert_eq!(err.to_string(), "unknown token key version 0");
+}
+
+#[tokio::test]
+async fn events_and_counts() {
+    let db = storage().await;
+    let since = Utc::now() - Duration::minutes(1);
+    db.upsert_user(profile(CHAT_ID, "Student")).await.unwrap();
+    db.upsert_user(profile(OTHER_CHAT_ID, "other"))
+        .await
+        .unwrap();
+    db.mark_user_inactive(OTHER_CHAT_ID, "blocked")
+        .await
+        .unwrap();
+
+    db.add_event(CHAT_ID, "solve", None).await.unwrap();
+    db.add_event(CHAT_ID, "solve", Some("not_found"))
+        .await
+        .unwrap();
+    db.add_event(OTHER_CHAT_ID, "help", None).await.unwrap();
+
+    assert_eq!(
+        db.get_command_counts(since).await.unwrap(),
+        [("solve".to_owned(), 2), ("help".to_owned(), 1)]
+    );
+    assert_eq!(
+        db.get_error_counts(since).await.unwrap(),
+        [("not_found".to_owned(), 1)]
+    );
+    assert!(db
+        .get_command_counts(Utc::now() + Duration::minutes(1))
+        .await
+        .unwrap()
+        .is_empty());
+
+    let users = db.get_user_counts(since).await.unwrap();
+    assert_eq!(
+        (users.total, users.inactive, users.active, users.new),
+        (1, 1, 1, 1)
+    );
+    assert_eq!(
+        db.find_user_by_username("student").await.unwrap(),
+        Some(CHAT_ID)
+    );
+
+    let summary = db.get_user_summary(CHAT_ID, 1).await.unwrap();
+    assert_eq!(
+        summary.profile.unwrap().username.as_deref(),
+        Some("Student")
+    );
+    assert_eq!(summary.recent_events.len(), 1);
+    assert_eq!(summary.recent_events[0].error.as_deref(), Some("not_found"));
+}
+
+#[tokio::test]
+async fn broadcasts() {
+    let db = storage().await;
+    db.upsert_user(profile(CHAT_ID, "student")).await.unwrap();
+    db.upsert_user(profile(OTHER_CHAT_ID, "other"))
+        .await
+        .unwrap();
+    assert!(db
+        .set_broadcasts_enabled(OTHER_CHAT_ID, false)
+        .await
+        .unwrap());
+    assert!(!db
+        .set_broadcasts_enabled(OTHER_CHAT_ID, false)
+        .await
+        .unwrap());
+
+    assert_eq!(db.get_broadcast_recipients(false).await.unwrap(), [CHAT_ID]);
+    let recipients = db.get_broadcast_recipients(true).await.unwrap();
+    assert_eq!(recipients, [CHAT_ID, OTHER_CHAT_ID]);
+
+    let id = db
+        .create_broadcast(NewBroadcast {
+            author_chat_id: CHAT_ID,
+            text: "Привет",
+            parse_mode: None,
+            attachment_kind: None,
+            attachment_file_id: None,
+            critical: true,
+        })
+        .await
+        .unwrap();
+    assert!(db.next_broadcast().await.unwrap().is_none());
+
+    assert!(db.queue_broadcast(id, 1, &recipients).await.unwrap());
+    assert!(!db.queue_broadcast(id, 1, &recipients).await.unwrap());
+    assert!(!db.cancel_broadcast(id).await.unwrap());
+    let broadcast = db.next_broadcast().await.unwrap().unwrap();
+    assert_eq!(broadcast.id, id);
+    assert_eq!(broadcast.text, "Привет");
+    assert_eq!(broadcast.progress_message_id, Some(1));
+
+    assert_eq!(db.get_pending_recipients(id, 10).await.unwrap(), recipients);
+    db.set_recipient_status(id, CHAT_ID, "delivered", None)
+        .await
+        .unwrap();
+    db.set_recipient_status(id, OTHER_CHAT_ID, "blocked", Some("bot was blocked"))
+        .await
+        .unwrap();
+    assert!(db.get_pending_recipients(id, 10).await.unwrap().is_empty());
+
+    let counts = db.get_broadcast_counts(id).await.unwrap();
+    assert_eq!(
+        (
+            counts.total,
+            counts.pending,
+            counts.delivered,
+            counts.blocked,
+            counts.failed
+        ),
+        (2, 0, 1, 1, 0)
+    );
+
+    let deliveries = db
+        .export_user(OTHER_CHAT_ID)
+        .await
+        .unwrap()
+        .broadcast_deliveries;
+    assert_eq!(deliveries.len(), 1);
+    assert_eq!(deliveries[0].broadcast_id, id);
+    assert_eq!(deliveries[0].status, "blocked");
+    assert_eq!(deliveries[0].error.as_deref(), Some("bot was blocked"));
+
+    db.set_broadcast_status(id, "finished").await.unwrap();
+    assert!(db.next_broadcast().await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn forget_user() {
+    let db = storage().await;
+    db.set_token(CHAT_ID, "token").await.unwrap();
+    db.update_dialogue(CHAT_ID, serde_json::json!("ReceiveEmail"))
+        .await
+        .unwrap();
+    db.set_consent(CHAT_ID, 1).await.unwrap();
+    db.upsert_user(profile(CHAT_ID, "student")).await.unwrap();
+    db.set_broadcasts_enabled(CHAT_ID, false).await.unwrap();
+    db.add_event(CHAT_ID, "help", None).await.unwrap();
+    let id = db
+        .create_broadcast(NewBroadcast {
+            author_chat_id: OTHER_CHAT_ID,
+            text: "Привет",
+            parse_mode: None,
+            attachment_kind: None,
+            attachment_file_id: None,
+            critical: true,
+        })
+        .await
+        .unwrap();
+    db.queue_broadcast(id, 1, &[CHAT_ID]).await.unwrap();
+    db.add_event(OTHER_CHAT_ID, "help", None).await.unwrap();
+
+    let forgotten = db.forget_user(CHAT_ID).await.unwrap();
+    assert!(forgotten.token);
+    assert!(forgotten.dialogue);
+    assert!(forgotten.consent);
+    assert!(forgotten.broadcast_deliveries);
+    assert!(forgotten.preferences);
+    assert!(forgotten.profile);
+    assert!(forgotten.events);
+
+    let export = db.export_user(CHAT_ID).await.unwrap();
+    assert!(export.profile.is_none());
+    assert!(export.token.is_none());
+    assert!(export.dialogue.is_none());
+    assert!(export.consent.is_none());
+    assert!(export.preferences.is_none());
+    assert!(export.broadcast_deliveries.is_empty());
+    assert!(export.events.is_empty());
+    // Other chats are untouched
+    assert_eq!(db.export_user(OTHER_CHAT_ID).await.unwrap().events.len(), 1);
+
+    let forgotten = db.forget_user(CHAT_ID).await.unwrap();
+    assert!(!forgotten.token && !forgotten.events && !forgotten.profile);
+}
+
+#[tokio::test]
+async fn purge_stale_dialogues() {
+    let db = storage().await;
+    db.update_dialogue(CHAT_ID, serde_json::json!("ReceiveEmail"))
+        .await
+        .unwrap();
+    db.update_dialogue(OTHER_CHAT_ID, serde_json::json!("ReceiveEmail"))
+        .await
+        .unwrap();
+    sqlx::query("UPDATE dialogues SET updated_at = ?1 WHERE chat_id = ?2")
+        .bind(Utc::now() - Duration::hours(25))
+        .bind(CHAT_ID)
+        .execute(&db.pool)
+        .await
+        .unwrap();
+
+    assert_eq!(db.purge_stale_dialogues(24).await.unwrap(), 1);
+    assert!(db.get_dialogue(CHAT_ID).await.unwrap().is_none());
+    assert!(db.get_dialogue(OTHER_CHAT_ID).await.unwrap().is_some());
+}
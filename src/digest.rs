@@ -0,0 +1,118 @@
+use std::{fmt::Write as _, time::Duration};
+
+use anyhow::{Context, Result};
+use chrono::{Local, NaiveTime, Utc};
+use teloxide::prelude::*;
+use tracing::*;
+
+use crate::{
+    db::Db,
+    engine::{self, MatetechConfig},
+    Bot,
+};
+
+/// How often to check whether it's time to send the digest.
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// How many most frequent error kinds to include.
+const TOP_ERRORS: usize = 3;
+
+/// Daily summary sent to all admins at `DIGEST_TIME` (`HH:MM` in server local
+/// time, 09:00 by default).
+#[derive(Debug, Clone)]
+pub struct Digest {
+    pub time: NaiveTime,
+}
+
+impl Digest {
+    pub fn from_env() -> Result<Self> {
+        let time = match std::env::var("DIGEST_TIME") {
+            Ok(t) => NaiveTime::parse_from_str(t.trim(), "%H:%M")
+                .context("DIGEST_TIME must be in HH:MM format")?,
+            Err(_) => NaiveTime::from_hms_opt(9, 0, 0).expect("valid time"),
+        };
+        Ok(Self { time })
+    }
+
+    /// Text of the digest for the last 24 hours.
+    async fn report(&self, db: &Db, matetech: &MatetechConfig) -> Result<String> {
+        let since = Utc::now() - chrono::Duration::days(1);
+        let users = db.get_user_counts(since).await?;
+        let commands = db.get_command_counts(since).await?;
+        let errors = db.get_error_counts(since).await?;
+        let total: i64 = commands.iter().map(|(_, count)| count).sum();
+
+        let mut text = String::from("Сводка за сутки.\n\n");
+        writeln!(
+            text,
+            "Новых пользователей: {}, активных: {}",
+            users.new, users.active
+        )?;
+
+        let by_command: Vec<String> = commands
+            .iter()
+            .map(|(command, count)| format!("/{command} {count}"))
+            .collect();
+        writeln!(text, "Команд: {total}")?;
+        if !by_command.is_empty() {
+            writeln!(text, "{}", by_command.join(", "))?;
+        }
+
+        let top_errors: Vec<String> = errors
+            .iter()
+            .take(TOP_ERRORS)
+            .map(|(error, count)| format!("{error} {count}"))
+            .collect();
+        if top_errors.is_empty() {
+            writeln!(text, "Ошибок не было")?;
+        } else {
+            writeln!(text, "Частые ошибки: {}", top_errors.join(", "))?;
+        }
+
+        match engine::check_availability(matetech).await {
+            Ok(elapsed) => writeln!(
+                text,
+                "API дисткурсов доступно, ответ за {} мс",
+                elapsed.as_millis()
+            )?,
+            Err(e) => writeln!(text, "API дисткурсов недоступно: {e}")?,
+        }
+
+        Ok(text)
+    }
+
+    async fn send(&self, db: &Db, bot: &Bot, matetech: &MatetechConfig) -> Result<()> {
+        let text = self.report(db, matetech).await?;
+        for admin in db.get_admins().await? {
+            if let Err(e) = bot.send_message(ChatId(admin), &text).await {
+                warn!("can't send digest to admin {admin}: {}", e);
+            }
+        }
+        info!("sent daily digest");
+        Ok(())
+    }
+
+    /// Sends the digest once a day in background. If the bot starts after
+    /// the digest time, the digest is sent tomorrow.
+    pub fn spawn(self, db: Db, bot: Bot, matetech: MatetechConfig) {
+        tokio::spawn(async move {
+            let now = Local::now();
+            let mut last_sent = (now.time() >= self.time).then(|| now.date_naive());
+
+            let mut interval = tokio::time::interval(CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+
+                let now = Local::now();
+                if now.time() < self.time || last_sent == Some(now.date_naive()) {
+                    continue;
+                }
+                last_sent = Some(now.date_naive());
+
+                if let Err(e) = self.send(&db, &bot, &matetech).await {
+                    error!("daily digest failed: {:?}", e);
+                    sentry::integrations::anyhow::capture_anyhow(&e);
+                }
+            }
+        });
+    }
+}
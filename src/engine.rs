@@ -1,6 +1,6 @@
 use std::collections::{HashMap, HashSet};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use rand::seq::SliceRandom;
 use reqwest::StatusCode;
 use sentry::capture_error;
@@ -10,10 +10,17 @@ use serde::Serialize;
 use serde_json::json;
 use tracing::*;
 
+use crate::secret::Secret;
+
 #[derive(thiserror::Error, Debug)]
 pub enum MatetechError {
     #[error("invalid credentials: {0}")]
     InvalidCredentials(reqwest::Error),
+    /// Token expired or was revoked
+    #[error("unauthorized: {0}")]
+    Unauthorized(reqwest::Error),
+    /// Token works, but can't access this resource, e.g. an attempt started
+    /// from another account
     #[error("forbidden: {0}")]
     Forbidden(reqwest::Error),
     #[error("not found: {0}")]
@@ -24,11 +31,25 @@ pub enum MatetechError {
     Other(#[from] anyhow::Error),
 }
 
+impl MatetechError {
+    /// Variant name for statistics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::InvalidCredentials(_) => "invalid_credentials",
+            Self::Unauthorized(_) => "unauthorized",
+            Self::Forbidden(_) => "forbidden",
+            Self::NotFound(_) => "not_found",
+            Self::Unknown(_) => "unknown",
+            Self::Other(_) => "other",
+        }
+    }
+}
+
 impl From<reqwest::Error> for MatetechError {
     fn from(err: reqwest::Error) -> Self {
-        if err.status() == Some(reqwest::StatusCode::FORBIDDEN)
-            || err.status() == Some(reqwest::StatusCode::UNAUTHORIZED)
-        {
+        if err.status() == Some(reqwest::StatusCode::UNAUTHORIZED) {
+            Self::Unauthorized(err)
+        } else if err.status() == Some(reqwest::StatusCode::FORBIDDEN) {
             Self::Forbidden(err)
         } else {
             Self::Other(err.into())
@@ -36,6 +57,48 @@ impl From<reqwest::Error> for MatetechError {
     }
 }
 
+const DEFAULT_BASE_URL: &str = "https://api.matetech.ru/api/public/companies/3";
+
+/// Where Matetech API lives, `MATETECH_BASE_URL` overrides the default and
+/// must be HTTPS.
+#[derive(Debug, Clone)]
+pub struct MatetechConfig {
+    /// Without trailing slash
+    pub base_url: String,
+    /// Refuse plain HTTP, so passwords and tokens never leave unencrypted
+    https_only: bool,
+}
+
+impl MatetechConfig {
+    pub fn new(base_url: &str) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            https_only: true,
+        }
+    }
+
+    /// Allows plain HTTP for a local mock server.
+    #[cfg(test)]
+    pub fn allow_http(mut self) -> Self {
+        self.https_only = false;
+        self
+    }
+
+    pub fn from_env() -> Result<Self> {
+        let url =
+            std::env::var("MATETECH_BASE_URL").unwrap_or_else(|_| DEFAULT_BASE_URL.to_owned());
+        let url = url.trim();
+        if !url.starts_with("https://") {
+            bail!("MATETECH_BASE_URL must start with https://, got {url}");
+        }
+        Ok(Self::new(url))
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{path}", self.base_url)
+    }
+}
+
 const USER_AGENTS: [&str; 16] = [
     "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, \
      like Gecko) Chrome/111.0.0.0 Safari/537.36",
@@ -73,8 +136,9 @@ const USER_AGENTS: [&str; 16] = [
      Safari/604.1",
 ];
 
-async fn build_client() -> Result<reqwest::Client, MatetechError> {
+async fn build_client(config: &MatetechConfig) -> Result<reqwest::Client, MatetechError> {
     Ok(reqwest::ClientBuilder::new()
+        .https_only(config.https_only)
         .user_agent(
             USER_AGENTS
                 .choose(&mut rand::thread_rng())
@@ -84,13 +148,17 @@ async fn build_client() -> Result<reqwest::Client, MatetechError> {
         .build()?)
 }
 
-#[instrument(skip(password))]
-pub async fn login(username: &String, password: &String) -> Result<String, MatetechError> {
-    let client = build_client().await?;
+#[instrument]
+pub async fn login(
+    config: &MatetechConfig,
+    username: &String,
+    password: &Secret<String>,
+) -> Result<Secret<String>, MatetechError> {
+    let client = build_client(config).await?;
 
     let auth_request = json!({
         "email": username,
-        "password": password,
+        "password": password.expose(),
     });
 
     #[derive(Deserialize)]
@@ -103,7 +171,7 @@ pub async fn login(username: &String, password: &String) -> Result<String, Matet
     }
 
     let auth_response = (match client
-        .post("https://api.matetech.ru/api/public/companies/3/login")
+        .post(config.url("/login"))
         .json(&auth_request)
         .send()
         .await?
@@ -115,6 +183,8 @@ pub async fn login(username: &String, password: &String) -> Result<String, Matet
                 || e.status() == Some(StatusCode::UNPROCESSABLE_ENTITY)
             {
                 return Err(MatetechError::InvalidCredentials(e));
+            } else if e.status() == Some(StatusCode::NOT_FOUND) {
+                return Err(MatetechError::NotFound(e));
             } else {
                 return Err(MatetechError::from(e));
             }
@@ -123,7 +193,26 @@ pub async fn login(username: &String, password: &String) -> Result<String, Matet
     .json::<AuthResponse>()
     .await?;
 
-    Ok(auth_response.data.access_token)
+    Ok(Secret::new(auth_response.data.access_token))
+}
+
+/// Checks that Matetech API responds, returns response time. Client errors
+/// like 404 still mean the API is up.
+#[instrument]
+pub async fn check_availability(
+    config: &MatetechConfig,
+) -> Result<std::time::Duration, MatetechError> {
+    let client = build_client(config).await?;
+
+    let started = std::time::Instant::now();
+    let response = client.get(config.url("")).send().await?;
+    if response.status().is_server_error() {
+        return Err(MatetechError::Unknown(format!(
+            "server error: {}",
+            response.status()
+        )));
+    }
+    Ok(started.elapsed())
 }
 
 #[derive(derivative::Derivative)]
@@ -131,12 +220,13 @@ pub async fn login(username: &String, password: &String) -> Result<String, Matet
 pub struct Solver {
     #[derivative(Debug = "ignore")]
     client: reqwest::Client,
+    config: MatetechConfig,
     attempt_id: u32,
     #[derivative(Debug = "ignore")]
     cached_test_result: Option<TestResult>,
 }
 
-#[derive(Debug, Eq, Hash, PartialEq)]
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub struct GeneratedAnswer {
     /// Question ID
     pub question_id: u32,
@@ -151,13 +241,18 @@ pub struct GeneratedAnswer {
 }
 
 impl Solver {
-    pub fn new(token: String, attempt_id: u32) -> Result<Self, MatetechError> {
+    pub fn new(
+        config: MatetechConfig,
+        token: Secret<String>,
+        attempt_id: u32,
+    ) -> Result<Self, MatetechError> {
         let mut headers = reqwest::header::HeaderMap::new();
-        let mut auth_value =
-            match reqwest::header::HeaderValue::from_str(format!("Bearer {token}").as_str()) {
-                Ok(h) => h,
-                Err(e) => return Err(MatetechError::Other(e.into())),
-            };
+        let mut auth_value = match reqwest::header::HeaderValue::from_str(
+            format!("Bearer {}", token.expose()).as_str(),
+        ) {
+            Ok(h) => h,
+            Err(e) => return Err(MatetechError::Other(e.into())),
+        };
         auth_value.set_sensitive(true);
         headers.insert(reqwest::header::AUTHORIZATION, auth_value);
 
@@ -166,12 +261,13 @@ impl Solver {
                 "Mozilla/5.0 (X11; Linux x86_64; rv:109.0) Gecko/20100101 \
                  Firefox/112.0",
             )
-            .https_only(true)
             .default_headers(headers)
+            .https_only(config.https_only)
             .build()?;
 
         Ok(Self {
             client,
+            config,
             attempt_id,
             cached_test_result: None,
         })
@@ -216,10 +312,10 @@ impl Solver {
             None => {
                 let mut test_result = (match self
                     .client
-                    .get(format!(
-                        "https://api.matetech.ru/api/public/companies/3/test_attempts/{}/result",
-                        self.attempt_id
-                    ))
+                    .get(
+                        self.config
+                            .url(&format!("/test_attempts/{}/result", self.attempt_id)),
+                    )
                     .send()
                     .await?
                     .error_for_status()
@@ -263,10 +359,12 @@ impl Solver {
 
     #[instrument(err)]
     async fn get_question(&self, question: u32) -> Result<QuestionInTest, MatetechError> {
-        Ok(self.client
-            .get(format!(
-                "https://api.matetech.ru/api/public/companies/3/test_attempts/{}/question/{question}", self.attempt_id
-            ))
+        Ok(self
+            .client
+            .get(self.config.url(&format!(
+                "/test_attempts/{}/question/{question}",
+                self.attempt_id
+            )))
             .send()
             .await?
             .error_for_status()?
@@ -328,9 +426,10 @@ impl Solver {
         let set_answer_request = json!({ "answer": answer });
 
         self.client
-            .post(format!(
-                "https://api.matetech.ru/api/public/companies/3/question_attempts/{question_attempt}/answer"
-            ))
+            .post(
+                self.config
+                    .url(&format!("/question_attempts/{question_attempt}/answer")),
+            )
             .json(&set_answer_request)
             .send()
             .await?
@@ -676,3 +775,6 @@ struct CurrentAttempt {
     id: u32,
     // points: Option<u32>,
 }
+
+#[cfg(test)]
+mod tests;
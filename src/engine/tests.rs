@@ -0,0 +1,321 @@
+use std::path::Path;
+
+use serde::de::DeserializeOwned;
+use serde_json::json;
+use wiremock::{
+    matchers::{body_partial_json, method, path},
+    Mock, MockServer, ResponseTemplate,
+};
+
+use super::*;
+
+const EMAIL: &str = "student@example.com";
+const PASSWORD: &str = "correct-password";
+const TOKEN: &str = "test-token";
+const ATTEMPT_ID: u32 = 42;
+
+/// Local Matetech API serving a canned successful login for [`EMAIL`] and a
+/// test result for [`ATTEMPT_ID`].
+async fn mock_matetech() -> (MockServer, MatetechConfig) {
+    let server = MockServer::start().await;
+
+    Mock::given(method("POST"))
+        .and(path("/login"))
+        .and(body_partial_json(
+            json!({ "email": EMAIL, "password": PASSWORD }),
+        ))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "data": { "access_token": TOKEN }
+        })))
+        .mount(&server)
+        .await;
+
+    Mock::given(method("GET"))
+        .and(path(format!("/test_attempts/{ATTEMPT_ID}/result")))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "data": {
+                "questions": [[{
+                    "id": 1,
+                    "question": "Unsupported question",
+                    "type": "drawing",
+                    "answers": []
+                }]]
+            }
+        })))
+        .mount(&server)
+        .await;
+
+    let config = MatetechConfig::new(&server.uri()).allow_http();
+    (server, config)
+}
+
+/// Makes every request to `path` fail with `status`.
+async fn fail_with(server: &MockServer, http_method: &str, at: &str, status: u16) {
+    Mock::given(method(http_method))
+        .and(path(at))
+        .respond_with(ResponseTemplate::new(status))
+        .with_priority(1)
+        .mount(server)
+        .await;
+}
+
+async fn login_error(status: u16) -> MatetechError {
+    let (server, config) = mock_matetech().await;
+    fail_with(&server, "POST", "/login", status).await;
+
+    login(
+        &config,
+        &EMAIL.to_owned(),
+        &Secret::new(PASSWORD.to_owned()),
+    )
+    .await
+    .expect_err("login must fail")
+}
+
+#[tokio::test]
+async fn login_returns_token() {
+    let (_server, config) = mock_matetech().await;
+
+    let token = login(
+        &config,
+        &EMAIL.to_owned(),
+        &Secret::new(PASSWORD.to_owned()),
+    )
+    .await
+    .unwrap();
+    assert_eq!(token.expose(), TOKEN);
+}
+
+#[tokio::test]
+async fn login_maps_unauthorized_to_invalid_credentials() {
+    let err = login_error(401).await;
+    assert!(
+        matches!(err, MatetechError::InvalidCredentials(_)),
+        "{err:?}"
+    );
+}
+
+#[tokio::test]
+async fn login_maps_unprocessable_entity_to_invalid_credentials() {
+    let err = login_error(422).await;
+    assert!(
+        matches!(err, MatetechError::InvalidCredentials(_)),
+        "{err:?}"
+    );
+}
+
+#[tokio::test]
+async fn login_maps_not_found() {
+    let err = login_error(404).await;
+    assert!(matches!(err, MatetechError::NotFound(_)), "{err:?}");
+}
+
+#[tokio::test]
+async fn login_maps_forbidden() {
+    let err = login_error(403).await;
+    assert!(matches!(err, MatetechError::Forbidden(_)), "{err:?}");
+}
+
+#[tokio::test]
+async fn plain_http_is_refused_by_default() {
+    let (server, _) = mock_matetech().await;
+    let config = MatetechConfig::new(&server.uri());
+
+    login(
+        &config,
+        &EMAIL.to_owned(),
+        &Secret::new(PASSWORD.to_owned()),
+    )
+    .await
+    .expect_err("login must not send the password over HTTP");
+    let mut solver = Solver::new(config, Secret::new(TOKEN.to_owned()), ATTEMPT_ID).unwrap();
+    solver
+        .solve(false)
+        .await
+        .expect_err("solve must not send the token over HTTP");
+    assert!(server.received_requests().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn other_not_found_is_unexpected() {
+    let (server, _) = mock_matetech().await;
+    let err = reqwest::get(format!("{}/question_attempts/1/answer", server.uri()))
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap_err();
+
+    let err = MatetechError::from(err);
+    assert!(matches!(err, MatetechError::Other(_)), "{err:?}");
+}
+
+#[tokio::test]
+async fn solve_reads_test_result() {
+    let (_server, config) = mock_matetech().await;
+
+    let mut solver = Solver::new(config, Secret::new(TOKEN.to_owned()), ATTEMPT_ID).unwrap();
+    let (text, answers) = solver.solve(false).await.unwrap();
+    assert_eq!(text, "1-1: UNSUPPORTED: drawing\n");
+    assert_eq!(answers.len(), 1);
+}
+
+#[tokio::test]
+async fn solve_maps_missing_test_to_not_found() {
+    let (server, config) = mock_matetech().await;
+    fail_with(
+        &server,
+        "GET",
+        &format!("/test_attempts/{ATTEMPT_ID}/result"),
+        404,
+    )
+    .await;
+
+    let mut solver = Solver::new(config, Secret::new(TOKEN.to_owned()), ATTEMPT_ID).unwrap();
+    let err = solver.solve(false).await.expect_err("solve must fail");
+    assert!(matches!(err, MatetechError::NotFound(_)), "{err:?}");
+}
+
+#[tokio::test]
+async fn solve_maps_foreign_attempt_to_forbidden() {
+    let (server, config) = mock_matetech().await;
+    fail_with(
+        &server,
+        "GET",
+        &format!("/test_attempts/{ATTEMPT_ID}/result"),
+        403,
+    )
+    .await;
+
+    let mut solver = Solver::new(config, Secret::new(TOKEN.to_owned()), ATTEMPT_ID).unwrap();
+    let err = solver.solve(false).await.expect_err("solve must fail");
+    assert!(matches!(err, MatetechError::Forbidden(_)), "{err:?}");
+}
+
+#[tokio::test]
+async fn solve_maps_expired_token_to_unauthorized() {
+    let (server, config) = mock_matetech().await;
+    fail_with(
+        &server,
+        "GET",
+        &format!("/test_attempts/{ATTEMPT_ID}/result"),
+        401,
+    )
+    .await;
+
+    let mut solver = Solver::new(config, Secret::new(TOKEN.to_owned()), ATTEMPT_ID).unwrap();
+    let err = solver.solve(false).await.expect_err("solve must fail");
+    assert!(matches!(err, MatetechError::Unauthorized(_)), "{err:?}");
+}
+
+/// Deserializes recorded (anonymised) upstream response from
+/// `tests/fixtures/matetech`, panicking with the path to the bad field.
+fn parse_fixture<T: DeserializeOwned>(name: &str) -> T {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures/matetech")
+        .join(name);
+    let json = std::fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("can't read {}: {e}", path.display()));
+    parse_json(&json).unwrap_or_else(|e| panic!("{name}: {e}"))
+}
+
+fn parse_json<T: DeserializeOwned>(json: &str) -> Result<T, String> {
+    let de = &mut serde_json::Deserializer::from_str(json);
+    serde_path_to_error::deserialize(de).map_err(|e| format!("at `{}`: {}", e.path(), e.inner()))
+}
+
+#[test]
+fn test_result_fixture() {
+    let result: TestResult = parse_fixture("test_result.json");
+
+    let types: Vec<&str> = result
+        .data
+        .questions
+        .iter()
+        .flatten()
+        .map(|q| q.r#type.as_str())
+        .collect();
+    assert_eq!(
+        types,
+        [
+            "radio",
+            "checkbox",
+            "numeric_input",
+            "input",
+            "sort",
+            "correspondence",
+            "text_input",
+            "select_input"
+        ]
+    );
+
+    let correspondence = &result.data.questions[1][1];
+    assert_eq!(
+        correspondence
+            .correspondence_links
+            .as_ref()
+            .map(|links| links.len()),
+        Some(2)
+    );
+    let select_input = &result.data.questions[1][3];
+    assert_eq!(
+        select_input
+            .select_input_items
+            .as_ref()
+            .map(|items| items.len()),
+        Some(2)
+    );
+}
+
+#[test]
+fn question_fixture() {
+    let question: Question = parse_fixture("question.json");
+    assert_eq!(question.id, 2006);
+    assert_eq!(question.r#type, "correspondence");
+    assert_eq!(question.answers.len(), 2);
+    assert!(question.select_input_items.is_none());
+}
+
+#[test]
+fn answer_fixture() {
+    let answer: Answer = parse_fixture("answer.json");
+    assert_eq!(answer.id, 30051);
+    assert_eq!(answer.sort, Some(1));
+    assert_eq!(answer.value.as_deref(), Some("Третий шаг"));
+    assert_eq!(answer.user_correct, Some(false));
+    assert_eq!(answer.correct_position, Some(3));
+}
+
+#[test]
+fn correspondence_link_fixture() {
+    let link: CorrespondenceLink = parse_fixture("correspondence_link.json");
+    assert_eq!((link.main_id, link.dependent_id), (30061, 30064));
+}
+
+#[test]
+fn select_input_item_fixture() {
+    let item: SelectInputItem = parse_fixture("select_input_item.json");
+    assert_eq!(item.id, 40001);
+    assert_eq!(item.answer_id, 30081);
+    assert_eq!(item.value, "верно");
+    assert!(item.correct);
+}
+
+#[test]
+fn question_in_test_fixture() {
+    let question: QuestionInTest = parse_fixture("question_in_test.json");
+    assert_eq!(question.data.current_attempt.id, 500001);
+}
+
+#[test]
+fn deserialization_error_shows_field_path() {
+    let json = r#"{ "data": { "questions": [[{
+        "id": 1, "question": "q", "type": "radio",
+        "answers": [{ "id": "not a number" }]
+    }]] } }"#;
+
+    let err = parse_json::<TestResult>(json).unwrap_err();
+    assert!(
+        err.starts_with("at `data.questions[0][0].answers[0].id`:"),
+        "{err}"
+    );
+}
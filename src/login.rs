@@ -0,0 +1,182 @@
+use serde::{Deserialize, Serialize};
+use teloxide::{dispatching::dialogue::Dialogue, prelude::*, utils::command::ParseError};
+use tracing::*;
+
+use crate::{
+    db::{self, Db},
+    engine::{self, MatetechConfig, MatetechError},
+    secret::Secret,
+    Bot,
+};
+
+/// State of the interactive `/login` flow, persisted in the `dialogues` table.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub enum LoginState {
+    #[default]
+    Idle,
+    ReceiveEmail,
+    ReceivePassword {
+        email: String,
+    },
+}
+
+pub type LoginDialogue = Dialogue<LoginState, db::DialogueStorage>;
+
+#[derive(Clone, Debug)]
+pub struct Credentials {
+    pub login: String,
+    pub password: Secret<String>,
+}
+
+/// `/login` starts the dialogue, `/login email password` logs in right away.
+pub fn parse_login(input: String) -> Result<(Option<Credentials>,), ParseError> {
+    let args: Vec<&str> = input.split_whitespace().collect();
+    match args[..] {
+        [] => Ok((None,)),
+        [login, password] => Ok((Some(Credentials {
+            login: login.to_owned(),
+            password: Secret::new(password.to_owned()),
+        }),)),
+        [_] => Err(ParseError::TooFewArguments {
+            expected: 2,
+            found: 1,
+            message: "Expected email and password".to_owned(),
+        }),
+        _ => Err(ParseError::TooManyArguments {
+            expected: 2,
+            found: args.len(),
+            message: "Expected email and password".to_owned(),
+        }),
+    }
+}
+
+pub async fn start(bot: &Bot, dialogue: &LoginDialogue) -> anyhow::Result<()> {
+    dialogue.update(LoginState::ReceiveEmail).await?;
+    bot.send_message(
+        dialogue.chat_id(),
+        "Отправьте почту от аккаунта дисткурсов. Отменить вход: /cancel.",
+    )
+    .await?;
+    Ok(())
+}
+
+/// Rough check, so that a test link sent after an abandoned `/login` isn't
+/// taken as the email.
+fn is_email(text: &str) -> bool {
+    text.contains('@')
+        && !text.contains(char::is_whitespace)
+        && crate::parse_solve(text.to_owned()).is_err()
+}
+
+#[instrument(skip_all, fields(chat_id = %msg.chat.id))]
+pub async fn receive_email(bot: Bot, dialogue: LoginDialogue, msg: Message) -> anyhow::Result<()> {
+    let Some(email) = msg.text().map(str::trim) else {
+        bot.send_message(msg.chat.id, "Отправьте почту текстом.")
+            .await?;
+        return Ok(());
+    };
+    if !is_email(email) {
+        bot.send_message(
+            msg.chat.id,
+            "Это не похоже на почту. Отправьте почту от аккаунта дисткурсов \
+             или отмените вход: /cancel.",
+        )
+        .await?;
+        return Ok(());
+    }
+
+    dialogue
+        .update(LoginState::ReceivePassword {
+            email: email.to_owned(),
+        })
+        .await?;
+    if let Err(e) = bot.delete_message(msg.chat.id, msg.id).await {
+        warn!("can't delete email message: {}", e);
+    }
+
+    bot.send_message(
+        msg.chat.id,
+        "Теперь отправьте пароль. Сообщение с паролем будет сразу удалено.",
+    )
+    .await?;
+    Ok(())
+}
+
+#[instrument(skip_all, fields(chat_id = %msg.chat.id))]
+pub async fn receive_password(
+    db: Db,
+    bot: Bot,
+    matetech: MatetechConfig,
+    dialogue: LoginDialogue,
+    msg: Message,
+    email: String,
+) -> anyhow::Result<()> {
+    let Some(password) = msg.text() else {
+        bot.send_message(msg.chat.id, "Отправьте пароль текстом.")
+            .await?;
+        return Ok(());
+    };
+    let password = Secret::new(password.to_owned());
+
+    dialogue.exit().await?;
+    let result = login(&db, &bot, &matetech, &msg, &email, &password).await;
+    crate::record_event(&db, msg.chat.id, "login", &result).await;
+    result.map(|_| ())
+}
+
+/// Logs in with credentials from `msg` and deletes it afterwards. Returns
+/// [`MatetechError::kind`] of the error reported to the user, if any.
+pub async fn login(
+    db: &Db,
+    bot: &Bot,
+    matetech: &MatetechConfig,
+    msg: &Message,
+    login: &String,
+    password: &Secret<String>,
+) -> anyhow::Result<Option<&'static str>> {
+    let result = engine::login(matetech, login, password).await;
+    delete_login_message(bot, msg).await?;
+    match result {
+        Ok(token) => {
+            db.set_token(msg.chat.id.0, token.expose()).await?;
+            bot.send_message(msg.chat.id, format!("Вы вошли в аккаунт {login}."))
+                .await?;
+        }
+        Err(err) => match err {
+            MatetechError::InvalidCredentials(_) => {
+                bot.send_message(
+                    msg.chat.id,
+                    "Неверный логин или пароль. Попробуйте ещё раз: /login.",
+                )
+                .await?;
+                return Ok(Some(err.kind()));
+            }
+            _ => {
+                return Err(err.into());
+            }
+        },
+    };
+    Ok(None)
+}
+
+/// Deletes the message containing password and tells the user about it.
+pub async fn delete_login_message(bot: &Bot, msg: &Message) -> anyhow::Result<()> {
+    match bot.delete_message(msg.chat.id, msg.id).await {
+        Ok(_) => {
+            bot.send_message(
+                msg.chat.id,
+                "Сообщение с паролем удалено из чата в целях безопасности.",
+            )
+            .await?;
+        }
+        Err(e) => {
+            warn!("can't delete login message: {}", e);
+            bot.send_message(
+                msg.chat.id,
+                "Не удалось удалить сообщение с паролем, удалите его самостоятельно.",
+            )
+            .await?;
+        }
+    }
+    Ok(())
+}
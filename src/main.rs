@@ -1,23 +1,39 @@
-use std::{collections::BTreeMap, str::FromStr};
+use std::{collections::BTreeMap, str::FromStr, sync::Arc};
 
-use anyhow::Result;
-use engine::MatetechError;
+use admin::AdminCommand;
+use anyhow::{bail, Context, Result};
+use db::Db;
+use engine::{MatetechConfig, MatetechError};
+use login::{Credentials, LoginDialogue, LoginState};
 use once_cell::sync::Lazy;
 use regex::Regex;
+use secret::Secret;
 use sentry::{capture_error, protocol::Value};
 use sentry_tracing::EventFilter;
-use sqlx::PgPool;
 use teloxide::{
     adaptors::{throttle::Limits, Throttle},
+    dispatching::UpdateHandler,
     macros::BotCommands,
     prelude::*,
-    utils::command::ParseError,
+    types::InputFile,
+    utils::command::{BotCommands as _, ParseError},
 };
 use tracing::*;
 use tracing_subscriber::prelude::*;
 
+mod admin;
+mod bans;
+mod broadcast;
+mod consent;
+mod crypto;
 mod db;
+mod digest;
 mod engine;
+mod login;
+mod retention;
+mod secret;
+mod stats;
+mod users;
 
 type Bot = Throttle<teloxide::Bot>;
 
@@ -50,6 +66,8 @@ fn main() -> Result<()> {
                     release: sentry::release_name!(),
                     attach_stacktrace: true,
                     traces_sample_rate: 1.0,
+                    before_send: Some(Arc::new(secret::scrub_event)),
+                    before_breadcrumb: Some(Arc::new(secret::scrub_breadcrumb)),
                     ..Default::default()
                 },
             ));
@@ -61,16 +79,45 @@ fn main() -> Result<()> {
         }
     };
 
-    tokio::runtime::Builder::new_multi_thread()
+    let runtime = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
-        .build()?
-        .block_on(_main())
+        .build()?;
+
+    let mut args = std::env::args().skip(1);
+    match args.next().as_deref() {
+        None => runtime.block_on(_main()),
+        Some("rotate-token-key") => {
+            let batch_size = match args.next() {
+                Some(s) => s.parse().context("batch size must be a number")?,
+                None => 500,
+            };
+            runtime.block_on(rotate_token_key(batch_size))
+        }
+        Some(cmd) => bail!("unknown command: {cmd}"),
+    }
 }
 
-async fn _main() -> Result<()> {
+async fn connect_db() -> Result<Db> {
+    crypto::init_from_env()?;
+
     tracing::info!("Starting database...");
-    let db = sqlx::PgPool::connect(&std::env::var("DATABASE_URL")?).await?;
-    sqlx::migrate!().run(&db).await?;
+    db::connect(&std::env::var("DATABASE_URL")?).await
+}
+
+/// Offline operator command: re-encrypts stored tokens with the current
+/// `TOKEN_KEY`. Old keys must still be listed in `TOKEN_OLD_KEYS`.
+async fn rotate_token_key(batch_size: i64) -> Result<()> {
+    let db = connect_db().await?;
+    db.reencrypt_tokens(batch_size).await
+}
+
+async fn _main() -> Result<()> {
+    let matetech = MatetechConfig::from_env()?;
+    let db = connect_db().await?;
+    admin::bootstrap_from_env(&db).await?;
+
+    let retention = retention::Retention::from_env()?;
+    retention.clone().spawn(db.clone());
 
     tracing::info!("Starting bot...");
     let bot = teloxide::Bot::from_env().throttle(Limits {
@@ -78,12 +125,20 @@ async fn _main() -> Result<()> {
         ..Default::default()
     });
 
-    let handler = Update::filter_message()
-        .branch(dptree::entry().filter_command::<Command>().endpoint(answer))
-        .branch(dptree::endpoint(invalid_command));
+    let broadcasts = broadcast::BroadcastQueue::default();
+    broadcasts.spawn_worker(db.clone(), bot.clone());
+
+    digest::Digest::from_env()?.spawn(db.clone(), bot.clone(), matetech.clone());
 
-    Dispatcher::builder(bot, handler)
-        .dependencies(dptree::deps![db])
+    let dialogue_storage = db::DialogueStorage::new(db.clone());
+    Dispatcher::builder(bot, schema())
+        .dependencies(dptree::deps![
+            db,
+            dialogue_storage,
+            retention,
+            broadcasts,
+            matetech
+        ])
         .enable_ctrlc_handler()
         .build()
         .dispatch()
@@ -92,17 +147,68 @@ async fn _main() -> Result<()> {
     Ok(())
 }
 
+/// Routes updates to handlers, dependencies are the ones set up in `_main`.
+fn schema() -> UpdateHandler<anyhow::Error> {
+    // Admins are never refused, banned chats are dropped before anything is
+    // saved about them
+    let message_handler = Update::filter_message()
+        .branch(
+            dptree::entry()
+                .filter_command::<AdminCommand>()
+                .filter_async(admin::is_admin)
+                .inspect_async(users::track)
+                .endpoint(admin::answer),
+        )
+        .branch(dptree::filter_map_async(bans::find_ban).endpoint(bans::refuse))
+        .branch(
+            dptree::entry()
+                .inspect_async(users::track)
+                .enter_dialogue::<Message, db::DialogueStorage, LoginState>()
+                .branch(dptree::entry().filter_command::<Command>().endpoint(answer))
+                .branch(dptree::case![LoginState::ReceiveEmail].endpoint(login::receive_email))
+                .branch(
+                    dptree::case![LoginState::ReceivePassword { email }]
+                        .endpoint(login::receive_password),
+                )
+                .branch(dptree::endpoint(invalid_command)),
+        );
+
+    let callback_handler = Update::filter_callback_query()
+        .branch(
+            dptree::filter(broadcast::is_broadcast_callback).endpoint(broadcast::handle_callback),
+        )
+        .branch(dptree::filter_map_async(bans::find_callback_ban).endpoint(bans::refuse_callback))
+        .branch(dptree::filter(consent::is_consent_callback).endpoint(consent::handle_callback));
+
+    dptree::entry()
+        .branch(message_handler)
+        .branch(callback_handler)
+}
+
 #[derive(Debug, BotCommands, Clone)]
 #[command(rename_rule = "lowercase", description = "Доступные команды:")]
 enum Command {
-    #[command(
-        description = "Войти в аккаунт. /login логин пароль",
-        parse_with = "split"
-    )]
+    #[command(description = "off")]
+    Start,
+    #[command(description = "Войти в аккаунт.", parse_with = login::parse_login)]
     Login {
-        login: String,
-        password: String,
+        credentials: Option<Credentials>,
     },
+    #[command(description = "Отменить вход в аккаунт.")]
+    Cancel,
+    #[command(description = "Выйти из аккаунта и удалить сохранённый токен.")]
+    Logout,
+    #[command(
+        rename = "forget_me",
+        description = "Удалить все сохранённые о вас данные."
+    )]
+    ForgetMe,
+    #[command(description = "Получить все сохранённые о вас данные.")]
+    MyData,
+    #[command(description = "Отписаться от рассылок.")]
+    Unsubscribe,
+    #[command(description = "Подписаться на рассылки.")]
+    Subscribe,
     Speedrun {
         test_id: u32,
     },
@@ -110,9 +216,6 @@ enum Command {
     Solve {
         test_id: u32,
     },
-    Broadcast {
-        message: String,
-    },
     Help,
 }
 
@@ -135,8 +238,77 @@ fn parse_solve(input: String) -> Result<(u32,), ParseError> {
     }
 }
 
-#[instrument(skip(db, bot))]
-async fn answer(db: PgPool, bot: Bot, msg: Message, cmd: Command) -> anyhow::Result<()> {
+impl Command {
+    /// Name stored in the `events` table.
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Start => "start",
+            Self::Login { .. } => "login",
+            Self::Cancel => "cancel",
+            Self::Logout => "logout",
+            Self::ForgetMe => "forget_me",
+            Self::MyData => "mydata",
+            Self::Unsubscribe => "unsubscribe",
+            Self::Subscribe => "subscribe",
+            Self::Speedrun { .. } => "speedrun",
+            Self::Solve { .. } => "solve",
+            Self::Help => "help",
+        }
+    }
+}
+
+/// Handles the command and records it in the `events` table for `/stats`.
+async fn answer(
+    db: Db,
+    bot: Bot,
+    matetech: MatetechConfig,
+    dialogue: LoginDialogue,
+    msg: Message,
+    cmd: Command,
+) -> anyhow::Result<()> {
+    let chat_id = msg.chat.id;
+    let command = cmd.name();
+    // Nothing must be left after `/forget_me`
+    let record = !matches!(cmd, Command::ForgetMe);
+    let result = handle_command(db.clone(), bot, matetech, dialogue, msg, cmd).await;
+    if record {
+        record_event(&db, chat_id, command, &result).await;
+    }
+    result.map(|_| ())
+}
+
+/// Saves the event with the error kind returned by the handler, failures are
+/// only logged.
+async fn record_event(
+    db: &Db,
+    chat_id: ChatId,
+    command: &str,
+    result: &anyhow::Result<Option<&'static str>>,
+) {
+    let error = match result {
+        Ok(error) => *error,
+        Err(e) => Some(
+            e.downcast_ref::<MatetechError>()
+                .map_or("internal", MatetechError::kind),
+        ),
+    };
+    if let Err(e) = db.add_event(chat_id.0, command, error).await {
+        error!("can't save event: {:?}", e);
+    }
+}
+
+/// Returns [`MatetechError::kind`] of the error reported to the user, if any.
+#[instrument(skip(db, bot, matetech, dialogue, msg), fields(chat_id = %msg.chat.id))]
+async fn handle_command(
+    db: Db,
+    bot: Bot,
+    matetech: MatetechConfig,
+    dialogue: LoginDialogue,
+    msg: Message,
+    cmd: Command,
+) -> anyhow::Result<Option<&'static str>> {
+    let mut reported_error = None;
+
     sentry::start_session();
     sentry::configure_scope(|scope| {
         let mut map = BTreeMap::new();
@@ -154,46 +326,162 @@ async fn answer(db: PgPool, bot: Bot, msg: Message, cmd: Command) -> anyhow::Res
         }));
     });
 
+    // Any other command abandons unfinished `/login`, `/forget_me` reports it
+    // as deleted data
+    if !matches!(
+        cmd,
+        Command::Login { .. } | Command::Cancel | Command::ForgetMe
+    ) {
+        dialogue.exit().await?;
+    }
+
     match cmd {
-        Command::Login { login, password } => {
-            match engine::login(&login, &password).await {
-                Ok(token) => {
-                    db::set_token(&db, msg.chat.id.0, &token).await?;
-                    bot.send_message(msg.chat.id, format!("Вы вошли в аккаунт {login}."))
-                        .await?;
-                }
-                Err(err) => match err {
-                    MatetechError::InvalidCredentials(_) => {
-                        bot.send_message(msg.chat.id, "Неверный логин или пароль. Убедитесь, что у вас нет лишних пробелов, \
-                        переносов строки, и проверьте пример входа в аккаунт: https://t.me/onlinecpm/134.")
-                            .await?;
-                    }
-                    _ => {
-                        return Err(err.into());
-                    }
-                },
-            };
+        Command::Start => {
+            if consent::has_consent(&db, msg.chat.id).await? {
+                bot.send_message(msg.chat.id, HELP_TEXT).await?;
+            } else {
+                consent::send_terms(&bot, msg.chat.id).await?;
+            }
         }
-        Command::Broadcast { message } => {
-            if msg.chat.id == ChatId(1004106925) {
-                for user in db::get_all_users(&db).await? {
-                    if let Err(e) = bot.send_message(ChatId(user), message.clone()).await {
-                        bot.send_message(msg.chat.id, e.to_string()).await?;
-                    };
+        Command::Login { credentials } => {
+            if !msg.chat.is_private() {
+                if credentials.is_some() {
+                    login::delete_login_message(&bot, &msg).await?;
+                }
+                bot.send_message(
+                    msg.chat.id,
+                    "Входить в аккаунт можно только в личных сообщениях. \
+                     Напишите боту напрямую.",
+                )
+                .await?;
+                return Ok(None);
+            }
+
+            if !consent::has_consent(&db, msg.chat.id).await? {
+                if credentials.is_some() {
+                    login::delete_login_message(&bot, &msg).await?;
                 }
+                consent::send_terms(&bot, msg.chat.id).await?;
+                return Ok(None);
+            }
+
+            match credentials {
+                Some(Credentials { login, password }) => {
+                    dialogue.exit().await?;
+                    reported_error =
+                        login::login(&db, &bot, &matetech, &msg, &login, &password).await?;
+                }
+                None => login::start(&bot, &dialogue).await?,
+            }
+        }
+        Command::Cancel => {
+            if matches!(
+                dialogue.get().await?,
+                Some(LoginState::ReceiveEmail | LoginState::ReceivePassword { .. })
+            ) {
+                dialogue.exit().await?;
+                bot.send_message(msg.chat.id, "Вход в аккаунт отменён.")
+                    .await?;
+            } else {
+                bot.send_message(msg.chat.id, "Нечего отменять.").await?;
+            }
+        }
+        Command::Logout => {
+            if db.delete_token(msg.chat.id.0).await? {
+                bot.send_message(
+                    msg.chat.id,
+                    "Вы вышли из аккаунта, сохранённый токен удалён.",
+                )
+                .await?;
+            } else {
+                bot.send_message(msg.chat.id, "Вы не входили в аккаунт.")
+                    .await?;
+            }
+        }
+        Command::ForgetMe => {
+            let forgotten = db.forget_user(msg.chat.id.0).await?;
+
+            let mut deleted = Vec::new();
+            if forgotten.token {
+                deleted.push("- токен для доступа к заданиям");
+            }
+            if forgotten.dialogue {
+                deleted.push("- незавершённый вход в аккаунт");
+            }
+            if forgotten.consent {
+                deleted.push("- согласие с условиями использования");
+            }
+            if forgotten.broadcast_deliveries {
+                deleted.push("- история доставки рассылок");
+            }
+            if forgotten.preferences {
+                deleted.push("- настройки рассылок");
+            }
+            if forgotten.profile {
+                deleted.push("- имя пользователя и время последней активности");
+            }
+            if forgotten.events {
+                deleted.push("- история использованных команд");
+            }
+
+            if deleted.is_empty() {
+                bot.send_message(msg.chat.id, "Бот не хранит о вас никаких данных.")
+                    .await?;
+            } else {
+                bot.send_message(
+                    msg.chat.id,
+                    format!("Удалены данные:\n{}", deleted.join("\n")),
+                )
+                .await?;
+            }
+        }
+        Command::MyData => {
+            let export = db.export_user(msg.chat.id.0).await?;
+            bot.send_document(
+                msg.chat.id,
+                InputFile::memory(serde_json::to_vec_pretty(&export)?).file_name("mydata.json"),
+            )
+            .caption("Все данные, которые бот хранит о вас.")
+            .await?;
+        }
+        Command::Unsubscribe => {
+            if db.set_broadcasts_enabled(msg.chat.id.0, false).await? {
+                bot.send_message(
+                    msg.chat.id,
+                    "Вы отписались от рассылок. Сообщения о сбоях в работе бота \
+                     всё равно будут приходить. Подписаться снова: /subscribe.",
+                )
+                .await?;
+            } else {
+                bot.send_message(msg.chat.id, "Вы уже отписаны от рассылок.")
+                    .await?;
+            }
+        }
+        Command::Subscribe => {
+            if db.set_broadcasts_enabled(msg.chat.id.0, true).await? {
+                bot.send_message(msg.chat.id, "Вы подписались на рассылки.")
+                    .await?;
+            } else {
+                bot.send_message(msg.chat.id, "Вы уже подписаны на рассылки.")
+                    .await?;
             }
         }
         Command::Solve { test_id } | Command::Speedrun { test_id } => {
-            let Some(token) = db::get_token(&db, msg.chat.id.0).await? else {
+            if consent::is_outdated(&db, msg.chat.id).await? {
+                consent::send_terms(&bot, msg.chat.id).await?;
+                return Ok(None);
+            }
+
+            let Some(token) = db.get_token(msg.chat.id.0).await? else {
                 bot.send_message(
                     msg.chat.id,
                     "Ознакомьтесь с инструкцией по использованию: \
                      /help.\nНеобходимо авторизовать бота в аккаунт \
-                     дисткурсов.\n/login ваша_почта ваш_пароль.\
+                     дисткурсов: /login.\
                      \n\nПример: https://t.me/onlinecpm/134",
                 )
                 .await?;
-                return Ok(());
+                return Ok(None);
             };
 
             let speedrun = matches!(cmd, Command::Speedrun { .. });
@@ -209,11 +497,14 @@ async fn answer(db: PgPool, bot: Bot, msg: Message, cmd: Command) -> anyhow::Res
                 )
                 .await?;
 
-            let mut solver = engine::Solver::new(token, test_id)?;
-            match solver.solve(speedrun).await {
+            let mut solver = engine::Solver::new(matetech, Secret::new(token), test_id)?;
+            let result = solver.solve(speedrun).await;
+            reported_error = result.as_ref().err().map(MatetechError::kind);
+            match result {
                 Ok((answers_str, answers_set)) => {
+                    db.mark_token_valid(msg.chat.id.0).await?;
                     for ans in answers_set {
-                        db::save_answer(&db, &ans).await?;
+                        db.save_answer(&ans).await?;
                     }
 
                     bot.edit_message_text(
@@ -227,13 +518,23 @@ async fn answer(db: PgPool, bot: Bot, msg: Message, cmd: Command) -> anyhow::Res
                     .await?;
                 }
                 Err(err) => match err {
+                    MatetechError::Unauthorized(_) => {
+                        db.mark_token_invalid(msg.chat.id.0).await?;
+                        bot.edit_message_text(
+                            msg.chat.id,
+                            answers_msg.id,
+                            "Сохранённый вход устарел. Войдите в аккаунт \
+                             заново: /login.",
+                        )
+                        .await?;
+                    }
                     MatetechError::Forbidden(_) => {
                         bot.edit_message_text(
                             msg.chat.id,
                             answers_msg.id,
-                            "Доступ к тесту невозможен. Убедитесь, что вы \
-                             вошли в тот же аккаунт, с которого и запустили \
-                             тест и попробуйте перелогиниться (/login почта пароль).",
+                            "Доступ к тесту невозможен: тест запущен с \
+                             другого аккаунта. Войдите в тот аккаунт, с \
+                             которого запустили тест: /login.",
                         )
                         .await?;
                     }
@@ -260,20 +561,28 @@ async fn answer(db: PgPool, bot: Bot, msg: Message, cmd: Command) -> anyhow::Res
             }
         }
         Command::Help => {
-            bot.send_message(msg.chat.id, HELP_TEXT).await?;
+            if db.is_admin(msg.chat.id.0).await? {
+                bot.send_message(
+                    msg.chat.id,
+                    format!("{HELP_TEXT}\n\n{}", AdminCommand::descriptions()),
+                )
+                .await?;
+            } else {
+                bot.send_message(msg.chat.id, HELP_TEXT).await?;
+            }
         }
     }
 
     sentry::end_session();
 
-    Ok(())
+    Ok(reported_error)
 }
 
 const HELP_TEXT: &str = "\
 Корректная работа бота не гарантируется - будьте готовы решить тест \
      самостоятельно в случае проблем.\n\nИнструкция по решению тестов.\n1. \
-     Авторизуйте бота в аккаунт дисткурсов: /login ваша_почта ваш_пароль. Не \
-     вставляйте лишние пробелы или перенос строки. Данные для \
+     Авторизуйте бота в аккаунт дисткурсов: отправьте /login и следуйте \
+     инструкциям. Данные для \
      входа будут сохранены, в целях безопасности не рекомендуем использовать \
      этот же пароль на других сайтах.\n2. Начните любой тест и скопируйте \
      URL-адрес в адресной строке браузера.\n3. Отправьте ссылку на тест \
@@ -282,16 +591,25 @@ const HELP_TEXT: &str = "\
      тест.\n\nПример использования бота: https://t.me/onlinecpm/134\n\nВ случае \
      возникновения ошибок обращайтесь к @averyanalex";
 
-#[instrument(skip(db, bot))]
-async fn invalid_command(db: PgPool, bot: Bot, msg: Message) -> anyhow::Result<()> {
+#[instrument(skip(db, bot, matetech, dialogue, msg), fields(chat_id = %msg.chat.id))]
+async fn invalid_command(
+    db: Db,
+    bot: Bot,
+    matetech: MatetechConfig,
+    dialogue: LoginDialogue,
+    msg: Message,
+) -> anyhow::Result<()> {
     let Some(text) = msg.text() else {
-        answer(db, bot, msg, Command::Help).await?;
+        answer(db, bot, matetech, dialogue, msg, Command::Help).await?;
         return Ok(());
     };
     let Ok((test_id,)) = parse_solve(text.to_owned()) else {
-        answer(db, bot, msg, Command::Help).await?;
+        answer(db, bot, matetech, dialogue, msg, Command::Help).await?;
         return Ok(());
     };
-    answer(db, bot, msg, Command::Solve { test_id }).await?;
+    answer(db, bot, matetech, dialogue, msg, Command::Solve { test_id }).await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests;
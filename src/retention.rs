@@ -0,0 +1,57 @@
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use tracing::*;
+
+use crate::db::Db;
+
+const RUN_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Unfinished `/login` keeps the email, so it's dropped after a day.
+const DIALOGUE_RETENTION_HOURS: i32 = 24;
+
+/// Deletes tokens that haven't been used for `TOKEN_RETENTION_DAYS` days
+/// (180 by default) and logins abandoned for [`DIALOGUE_RETENTION_HOURS`].
+#[derive(Debug, Clone)]
+pub struct Retention {
+    pub days: i32,
+}
+
+impl Retention {
+    pub fn from_env() -> Result<Self> {
+        let days = match std::env::var("TOKEN_RETENTION_DAYS") {
+            Ok(d) => d
+                .trim()
+                .parse()
+                .context("TOKEN_RETENTION_DAYS must be a number")?,
+            Err(_) => 180,
+        };
+        if days < 1 {
+            bail!("TOKEN_RETENTION_DAYS must be at least 1, got {days}");
+        }
+        Ok(Self { days })
+    }
+
+    pub async fn run(&self, db: &Db) -> Result<u64> {
+        let purged = db.purge_inactive_tokens(self.days).await?;
+        info!("purged {purged} tokens not used for {} days", self.days);
+
+        let dialogues = db.purge_stale_dialogues(DIALOGUE_RETENTION_HOURS).await?;
+        info!("purged {dialogues} abandoned logins");
+        Ok(purged)
+    }
+
+    /// Runs retention once a day in background.
+    pub fn spawn(self, db: Db) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(RUN_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(e) = self.run(&db).await {
+                    error!("token retention failed: {:?}", e);
+                    sentry::integrations::anyhow::capture_anyhow(&e);
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,119 @@
+use std::{convert::Infallible, fmt, str::FromStr};
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+use sentry::protocol::{Breadcrumb, Context, Event, Map, Value};
+
+/// Value that must never appear in logs. `Debug` and `Display` print `***`.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret<T>(T);
+
+impl<T> Secret<T> {
+    pub fn new(value: T) -> Self {
+        Self(value)
+    }
+
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("***")
+    }
+}
+
+impl<T> fmt::Display for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("***")
+    }
+}
+
+impl FromStr for Secret<String> {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(s.to_owned()))
+    }
+}
+
+/// Replaces anything that looks like a password or token with `***`.
+fn scrub(text: &str) -> String {
+    static PATTERNS: Lazy<[(Regex, &str); 3]> = Lazy::new(|| {
+        [
+            (
+                Regex::new(r"(?i)(bearer\s+)[\w\-.~+/]+=*").unwrap(),
+                "${1}***",
+            ),
+            (
+                Regex::new(
+                    r#"(?i)(\b(?:password|access_token|token)"?\s*[:=]\s*)("[^"]*"|[^\s,}]+)"#,
+                )
+                .unwrap(),
+                "${1}***",
+            ),
+            (Regex::new(r"(/login\s+\S+\s+)\S+").unwrap(), "${1}***"),
+        ]
+    });
+
+    let mut text = text.to_owned();
+    for (regex, replacement) in PATTERNS.iter() {
+        if regex.is_match(&text) {
+            text = regex.replace_all(&text, *replacement).into_owned();
+        }
+    }
+    text
+}
+
+fn scrub_value(value: &mut Value) {
+    match value {
+        Value::String(s) => *s = scrub(s),
+        Value::Array(values) => values.iter_mut().for_each(scrub_value),
+        Value::Object(map) => map.values_mut().for_each(scrub_value),
+        _ => {}
+    }
+}
+
+fn scrub_map(map: &mut Map<String, Value>) {
+    map.values_mut().for_each(scrub_value);
+}
+
+pub fn scrub_breadcrumb(mut breadcrumb: Breadcrumb) -> Option<Breadcrumb> {
+    if let Some(message) = &mut breadcrumb.message {
+        *message = scrub(message);
+    }
+    scrub_map(&mut breadcrumb.data);
+    Some(breadcrumb)
+}
+
+/// Sentry `before_send` hook, last line of defence for secrets that slipped
+/// into messages, exceptions, breadcrumbs or span fields.
+pub fn scrub_event(mut event: Event<'static>) -> Option<Event<'static>> {
+    if let Some(message) = &mut event.message {
+        *message = scrub(message);
+    }
+    if let Some(logentry) = &mut event.logentry {
+        logentry.message = scrub(&logentry.message);
+        logentry.params.iter_mut().for_each(scrub_value);
+    }
+    for exception in &mut event.exception.values {
+        if let Some(value) = &mut exception.value {
+            *value = scrub(value);
+        }
+    }
+    event.breadcrumbs.values = std::mem::take(&mut event.breadcrumbs.values)
+        .into_iter()
+        .filter_map(scrub_breadcrumb)
+        .collect();
+    for context in event.contexts.values_mut() {
+        if let Context::Other(map) = context {
+            scrub_map(map);
+        }
+    }
+    scrub_map(&mut event.extra);
+    Some(event)
+}
+
+#[cfg(test)]
+mod tests;
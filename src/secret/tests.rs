@@ -0,0 +1,69 @@
+use super::*;
+use crate::login::Credentials;
+
+#[test]
+fn scrubs_login_command() {
+    assert_eq!(scrub("/login a@b.c hunter2"), "/login a@b.c ***");
+}
+
+#[test]
+fn scrubs_bearer_token() {
+    assert_eq!(
+        scrub("Authorization: Bearer xyz"),
+        "Authorization: Bearer ***"
+    );
+}
+
+#[test]
+fn scrubs_json_password() {
+    assert_eq!(
+        scrub(r#"{"email": "a@b.c", "password": "hunter2"}"#),
+        r#"{"email": "a@b.c", "password": ***}"#
+    );
+}
+
+#[test]
+fn scrubs_token_parameter() {
+    assert_eq!(scrub("GET /api?token=abc123"), "GET /api?token=***");
+    assert_eq!(scrub("access_token: abc123"), "access_token: ***");
+}
+
+#[test]
+fn keeps_unrelated_text() {
+    for text in [
+        "can't find attempt 42 for chat 100",
+        "tokens: 5",
+        "re-encrypted tokens: 10/20",
+        "token_key_version: 2",
+    ] {
+        assert_eq!(scrub(text), text);
+    }
+}
+
+#[test]
+fn scrubs_nested_values() {
+    let mut value = serde_json::json!({
+        "request": { "body": ["password=hunter2"] },
+    });
+    scrub_value(&mut value);
+    assert!(!value.to_string().contains("hunter2"), "{value}");
+}
+
+#[test]
+fn secret_is_hidden_in_debug_and_display() {
+    let secret = Secret::new("hunter2".to_owned());
+    assert_eq!(format!("{secret:?}"), "***");
+    assert_eq!(secret.to_string(), "***");
+    assert_eq!(secret.expose(), "hunter2");
+}
+
+#[test]
+fn credentials_debug_hides_password() {
+    let credentials = Credentials {
+        login: "a@b.c".to_owned(),
+        password: Secret::new("hunter2".to_owned()),
+    };
+    let debug = format!("{credentials:?}");
+    assert!(!debug.contains("hunter2"), "{debug}");
+    assert!(debug.contains("a@b.c"), "{debug}");
+}
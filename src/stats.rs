@@ -0,0 +1,71 @@
+use std::fmt::Write as _;
+
+use anyhow::Result;
+use chrono::{DateTime, Duration, Local, Utc};
+
+use crate::db::Db;
+
+/// Period for command and error statistics.
+const EVENTS_PERIOD_DAYS: i64 = 30;
+
+fn start_of_today() -> DateTime<Utc> {
+    let now = Local::now();
+    now.date_naive()
+        .and_hms_opt(0, 0, 0)
+        .and_then(|t| t.and_local_timezone(Local).earliest())
+        .map_or(now, |t| t)
+        .with_timezone(&Utc)
+}
+
+/// Text of the `/stats` admin command.
+pub async fn report(db: &Db) -> Result<String> {
+    let now = Utc::now();
+    let periods = [
+        ("Сегодня", start_of_today()),
+        ("7 дней", now - Duration::days(7)),
+        ("30 дней", now - Duration::days(30)),
+    ];
+
+    let mut text = String::new();
+    for (i, (name, since)) in periods.into_iter().enumerate() {
+        let counts = db.get_user_counts(since).await?;
+        if i == 0 {
+            writeln!(
+                text,
+                "Пользователей: {}, недоступны: {}\n",
+                counts.total, counts.inactive
+            )?;
+        }
+        writeln!(
+            text,
+            "{name}: активных {}, новых {}",
+            counts.active, counts.new
+        )?;
+    }
+
+    let since = now - Duration::days(EVENTS_PERIOD_DAYS);
+    let commands = db.get_command_counts(since).await?;
+    let total: i64 = commands.iter().map(|(_, count)| count).sum();
+    writeln!(
+        text,
+        "\nКоманды за {EVENTS_PERIOD_DAYS} дней, всего {total}:"
+    )?;
+    for (command, count) in &commands {
+        writeln!(text, "/{command}: {count}")?;
+    }
+
+    let errors = db.get_error_counts(since).await?;
+    writeln!(text, "\nОшибки за {EVENTS_PERIOD_DAYS} дней:")?;
+    if errors.is_empty() {
+        writeln!(text, "нет")?;
+    }
+    for (error, count) in &errors {
+        writeln!(
+            text,
+            "{error}: {count} ({:.1}%)",
+            *count as f64 * 100.0 / total as f64
+        )?;
+    }
+
+    Ok(text)
+}
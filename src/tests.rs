@@ -0,0 +1,504 @@
+use std::{
+    ops::ControlFlow,
+    sync::atomic::{AtomicI32, Ordering},
+};
+
+use serde_json::json;
+use teloxide::types::Me;
+use wiremock::{
+    matchers::{method, path},
+    Mock, MockServer, Request, Respond, ResponseTemplate,
+};
+
+use super::*;
+use crate::{broadcast::BroadcastQueue, db::MemoryStorage, retention::Retention};
+
+const BOT_TOKEN: &str = "123456:test-token";
+const CHAT_ID: i64 = 100;
+const LINK: &str = "https://online.cpm-edu.ru/test?attempt_id=42";
+
+/// Local Bot API answering every method. Sent and edited messages are echoed
+/// back the way Telegram does, other methods just succeed.
+struct FakeBotApi {
+    next_message_id: AtomicI32,
+}
+
+impl Respond for FakeBotApi {
+    fn respond(&self, request: &Request) -> ResponseTemplate {
+        let result = match request_method(request).as_str() {
+            "sendmessage" | "editmessagetext" => {
+                let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+                let message_id = body
+                    .get("message_id")
+                    .cloned()
+                    .unwrap_or_else(|| self.next_message_id.fetch_add(1, Ordering::SeqCst).into());
+                json!({
+                    "message_id": message_id,
+                    "date": 0,
+                    "chat": { "id": body["chat_id"], "type": "private", "first_name": "Test" },
+                    "text": body["text"],
+                })
+            }
+            _ => json!(true),
+        };
+        ResponseTemplate::new(200).set_body_json(json!({ "ok": true, "result": result }))
+    }
+}
+
+/// Bot API method name, case-insensitive like Telegram itself.
+fn request_method(request: &Request) -> String {
+    request
+        .url
+        .path_segments()
+        .and_then(|mut s| s.next_back())
+        .unwrap_or_default()
+        .to_lowercase()
+}
+
+/// Outgoing `sendMessage` or `editMessageText` call.
+#[derive(Debug)]
+struct Sent {
+    method: String,
+    chat_id: i64,
+    message_id: Option<i64>,
+    text: String,
+}
+
+/// Runs updates through [`schema`] with in-memory storage, a fake Bot API and
+/// a Matetech server without any mocks, so every test attempt is not found.
+struct Harness {
+    telegram: MockServer,
+    matetech: MockServer,
+    db: Db,
+    bot: Bot,
+}
+
+impl Harness {
+    async fn new() -> Self {
+        let telegram = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(FakeBotApi {
+                next_message_id: AtomicI32::new(1000),
+            })
+            .mount(&telegram)
+            .await;
+
+        let bot = teloxide::Bot::new(BOT_TOKEN)
+            .set_api_url(reqwest::Url::parse(&telegram.uri()).unwrap())
+            .throttle(Limits {
+                messages_per_sec_chat: 100,
+                messages_per_min_chat: 1000,
+                messages_per_min_channel: 1000,
+                messages_per_sec_overall: 1000,
+            });
+
+        Self {
+            telegram,
+            matetech: MockServer::start().await,
+            db: Arc::new(MemoryStorage::default()),
+            bot,
+        }
+    }
+
+    /// Handles a private text message from [`CHAT_ID`].
+    async fn send(&self, text: &str) {
+        self.dispatch(json!({
+            "update_id": 1,
+            "message": {
+                "message_id": 1,
+                "date": 0,
+                "chat": { "id": CHAT_ID, "type": "private", "first_name": "Test" },
+                "from": { "id": CHAT_ID, "is_bot": false, "first_name": "Test" },
+                "text": text,
+            },
+        }))
+        .await;
+    }
+
+    /// Handles a press of the button with `data` under a bot message in the
+    /// private chat with [`CHAT_ID`].
+    async fn press(&self, data: &str) {
+        self.dispatch(json!({
+            "update_id": 1,
+            "callback_query": {
+                "id": "1",
+                "from": { "id": CHAT_ID, "is_bot": false, "first_name": "Test" },
+                "chat_instance": "1",
+                "data": data,
+                "message": {
+                    "message_id": 1000,
+                    "date": 0,
+                    "chat": { "id": CHAT_ID, "type": "private", "first_name": "Test" },
+                    "from": { "id": 1, "is_bot": true, "first_name": "Bot" },
+                    "text": "Условия",
+                },
+            },
+        }))
+        .await;
+    }
+
+    async fn dispatch(&self, update: serde_json::Value) {
+        // `Update` borrows keys while deserializing, so it can't be read from
+        // a `serde_json::Value`
+        let update: Update = serde_json::from_str(&update.to_string()).unwrap();
+        let me: Me = serde_json::from_value(json!({
+            "id": 1,
+            "is_bot": true,
+            "first_name": "Bot",
+            "username": "cpm_test_bot",
+            "can_join_groups": false,
+            "can_read_all_group_messages": false,
+            "supports_inline_queries": false,
+        }))
+        .unwrap();
+
+        let deps = dptree::deps![
+            update,
+            me,
+            self.bot.clone(),
+            self.db.clone(),
+            db::DialogueStorage::new(self.db.clone()),
+            Retention { days: 180 },
+            BroadcastQueue::default(),
+            MatetechConfig::new(&self.matetech.uri()).allow_http()
+        ];
+        match schema().dispatch(deps).await {
+            ControlFlow::Break(result) => result.unwrap(),
+            ControlFlow::Continue(_) => panic!("update was not handled"),
+        }
+    }
+
+    /// Messages sent or edited by the bot, in order.
+    async fn sent(&self) -> Vec<Sent> {
+        self.telegram
+            .received_requests()
+            .await
+            .unwrap()
+            .iter()
+            .filter_map(|request| {
+                let method = request_method(request);
+                if method != "sendmessage" && method != "editmessagetext" {
+                    return None;
+                }
+                let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+                Some(Sent {
+                    method,
+                    chat_id: body["chat_id"].as_i64().unwrap(),
+                    message_id: body["message_id"].as_i64(),
+                    text: body["text"].as_str().unwrap().to_owned(),
+                })
+            })
+            .collect()
+    }
+
+    async fn sent_texts(&self) -> Vec<String> {
+        self.sent().await.into_iter().map(|s| s.text).collect()
+    }
+}
+
+#[tokio::test]
+async fn help_command() {
+    let harness = Harness::new().await;
+    harness.send("/help").await;
+
+    let sent = harness.sent().await;
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].method, "sendmessage");
+    assert_eq!(sent[0].chat_id, CHAT_ID);
+    assert_eq!(sent[0].text, HELP_TEXT);
+}
+
+#[tokio::test]
+async fn unknown_text_falls_back_to_help() {
+    let harness = Harness::new().await;
+    harness.send("привет").await;
+
+    assert_eq!(harness.sent_texts().await, [HELP_TEXT]);
+}
+
+#[tokio::test]
+async fn unparsable_solve_falls_back_to_help() {
+    let harness = Harness::new().await;
+    harness.send("/solve не ссылка").await;
+
+    assert_eq!(harness.sent_texts().await, [HELP_TEXT]);
+}
+
+#[tokio::test]
+async fn link_without_token_asks_to_login() {
+    let harness = Harness::new().await;
+    harness.send(LINK).await;
+
+    let texts = harness.sent_texts().await;
+    assert_eq!(texts.len(), 1);
+    assert!(texts[0].contains("/login"), "{texts:?}");
+}
+
+#[tokio::test]
+async fn link_with_token_edits_progress_message() {
+    let harness = Harness::new().await;
+    harness.db.set_token(CHAT_ID, "token").await.unwrap();
+    harness.send(LINK).await;
+
+    let sent = harness.sent().await;
+    assert_eq!(sent.len(), 2, "{sent:?}");
+    assert_eq!(sent[0].method, "sendmessage");
+    assert_eq!(sent[0].text, "Решаем тест, это может занять до минуты...");
+    assert_eq!(sent[1].method, "editmessagetext");
+    assert_eq!(sent[1].message_id, Some(1000));
+    assert_eq!(
+        sent[1].text,
+        "Тест не найден, проверьте корректность ссылки."
+    );
+}
+
+#[tokio::test]
+async fn bare_attempt_id_is_solved() {
+    let harness = Harness::new().await;
+    harness.db.set_token(CHAT_ID, "token").await.unwrap();
+    harness.send("42").await;
+
+    let texts = harness.sent_texts().await;
+    assert_eq!(texts[0], "Решаем тест, это может занять до минуты...");
+}
+
+#[tokio::test]
+async fn start_without_consent_sends_terms() {
+    let harness = Harness::new().await;
+    harness.send("/start").await;
+
+    let texts = harness.sent_texts().await;
+    assert_eq!(texts.len(), 1);
+    assert!(texts[0].ends_with("Чтобы войти в аккаунт, примите эти условия."));
+}
+
+#[tokio::test]
+async fn banned_user_is_refused() {
+    let harness = Harness::new().await;
+    harness.db.add_ban(CHAT_ID, Some("спам"), 1).await.unwrap();
+    harness.send("/help").await;
+
+    assert_eq!(
+        harness.sent_texts().await,
+        ["Доступ к боту для вас ограничен. Причина: спам"]
+    );
+}
+
+#[tokio::test]
+async fn commands_are_recorded() {
+    let harness = Harness::new().await;
+    harness.db.set_token(CHAT_ID, "token").await.unwrap();
+    harness.send("/help").await;
+    harness.send(LINK).await;
+
+    let since = chrono::Utc::now() - chrono::Duration::minutes(1);
+    let mut commands = harness.db.get_command_counts(since).await.unwrap();
+    commands.sort();
+    assert_eq!(commands, [("help".to_owned(), 1), ("solve".to_owned(), 1)]);
+    assert_eq!(
+        harness.db.get_error_counts(since).await.unwrap(),
+        [("not_found".to_owned(), 1)]
+    );
+}
+
+/// Sends `/login` with the terms already accepted.
+async fn start_login(harness: &Harness) {
+    harness
+        .db
+        .set_consent(CHAT_ID, consent::TERMS_VERSION)
+        .await
+        .unwrap();
+    harness.send("/login").await;
+}
+
+async fn login_state(harness: &Harness) -> Option<serde_json::Value> {
+    harness.db.get_dialogue(CHAT_ID).await.unwrap()
+}
+
+#[tokio::test]
+async fn login_dialogue_rejects_link_as_email() {
+    let harness = Harness::new().await;
+    start_login(&harness).await;
+    harness.send(LINK).await;
+
+    let texts = harness.sent_texts().await;
+    assert_eq!(texts.len(), 2, "{texts:?}");
+    assert!(texts[1].starts_with("Это не похоже на почту."), "{texts:?}");
+    assert_eq!(login_state(&harness).await, Some(json!("ReceiveEmail")));
+}
+
+#[tokio::test]
+async fn login_dialogue_accepts_email() {
+    let harness = Harness::new().await;
+    start_login(&harness).await;
+    harness.send("a@b.c").await;
+
+    assert_eq!(
+        login_state(&harness).await,
+        Some(json!({ "ReceivePassword": { "email": "a@b.c" } }))
+    );
+}
+
+#[tokio::test]
+async fn other_command_exits_login_dialogue() {
+    let harness = Harness::new().await;
+    start_login(&harness).await;
+    harness.send("/help").await;
+    assert_eq!(login_state(&harness).await, None);
+
+    harness.send(LINK).await;
+    let texts = harness.sent_texts().await;
+    assert!(texts.last().unwrap().contains("/login"), "{texts:?}");
+}
+
+#[tokio::test]
+async fn cancel_exits_login_dialogue() {
+    let harness = Harness::new().await;
+    start_login(&harness).await;
+    harness.send("a@b.c").await;
+    harness.send("/cancel").await;
+
+    assert_eq!(login_state(&harness).await, None);
+    assert_eq!(
+        harness.sent_texts().await.last().unwrap(),
+        "Вход в аккаунт отменён."
+    );
+
+    harness.send("/cancel").await;
+    assert_eq!(
+        harness.sent_texts().await.last().unwrap(),
+        "Нечего отменять."
+    );
+}
+
+/// Makes Matetech answer the test result of [`LINK`] with `status`.
+async fn fail_test_result(harness: &Harness, status: u16) {
+    Mock::given(method("GET"))
+        .and(path("/test_attempts/42/result"))
+        .respond_with(ResponseTemplate::new(status))
+        .mount(&harness.matetech)
+        .await;
+}
+
+#[tokio::test]
+async fn foreign_attempt_keeps_token() {
+    let harness = Harness::new().await;
+    harness.db.set_token(CHAT_ID, "token").await.unwrap();
+    fail_test_result(&harness, 403).await;
+    harness.send(LINK).await;
+
+    let texts = harness.sent_texts().await;
+    assert!(texts[1].contains("другого аккаунта"), "{texts:?}");
+    assert_eq!(
+        harness.db.get_token(CHAT_ID).await.unwrap().as_deref(),
+        Some("token")
+    );
+}
+
+#[tokio::test]
+async fn expired_token_is_invalidated() {
+    let harness = Harness::new().await;
+    harness.db.set_token(CHAT_ID, "token").await.unwrap();
+    fail_test_result(&harness, 401).await;
+    harness.send(LINK).await;
+
+    let texts = harness.sent_texts().await;
+    assert!(
+        texts[1].starts_with("Сохранённый вход устарел."),
+        "{texts:?}"
+    );
+    assert_eq!(harness.db.get_token(CHAT_ID).await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn interactive_login_is_recorded() {
+    let harness = Harness::new().await;
+    Mock::given(method("POST"))
+        .and(path("/login"))
+        .respond_with(ResponseTemplate::new(401))
+        .mount(&harness.matetech)
+        .await;
+    start_login(&harness).await;
+    harness.send("a@b.c").await;
+    harness.send("wrong-password").await;
+
+    let texts = harness.sent_texts().await;
+    assert!(
+        texts
+            .last()
+            .unwrap()
+            .starts_with("Неверный логин или пароль."),
+        "{texts:?}"
+    );
+    let since = chrono::Utc::now() - chrono::Duration::minutes(1);
+    assert_eq!(
+        harness.db.get_command_counts(since).await.unwrap(),
+        [("login".to_owned(), 2)]
+    );
+    assert_eq!(
+        harness.db.get_error_counts(since).await.unwrap(),
+        [("invalid_credentials".to_owned(), 1)]
+    );
+}
+
+#[tokio::test]
+async fn outdated_consent_sends_terms_before_solving() {
+    let harness = Harness::new().await;
+    harness
+        .db
+        .set_consent(CHAT_ID, consent::TERMS_VERSION - 1)
+        .await
+        .unwrap();
+    harness.db.set_token(CHAT_ID, "token").await.unwrap();
+    harness.send(LINK).await;
+
+    let texts = harness.sent_texts().await;
+    assert_eq!(texts.len(), 1, "{texts:?}");
+    assert!(texts[0].ends_with("Чтобы войти в аккаунт, примите эти условия."));
+    assert!(harness
+        .matetech
+        .received_requests()
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[tokio::test]
+async fn banned_user_is_not_tracked() {
+    let harness = Harness::new().await;
+    let since = chrono::Utc::now() - chrono::Duration::minutes(1);
+    harness.db.add_ban(CHAT_ID, None, 1).await.unwrap();
+    harness.send("/help").await;
+
+    assert_eq!(harness.db.get_user_counts(since).await.unwrap().active, 0);
+    assert!(harness
+        .db
+        .get_command_counts(since)
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[tokio::test]
+async fn consent_button_is_accepted() {
+    let harness = Harness::new().await;
+    harness
+        .press(&format!("consent:{}", consent::TERMS_VERSION))
+        .await;
+
+    assert_eq!(
+        harness.db.get_consent_version(CHAT_ID).await.unwrap(),
+        Some(consent::TERMS_VERSION)
+    );
+}
+
+#[tokio::test]
+async fn banned_user_cannot_accept_terms() {
+    let harness = Harness::new().await;
+    harness.db.add_ban(CHAT_ID, None, 1).await.unwrap();
+    harness
+        .press(&format!("consent:{}", consent::TERMS_VERSION))
+        .await;
+
+    assert_eq!(harness.db.get_consent_version(CHAT_ID).await.unwrap(), None);
+}
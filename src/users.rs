@@ -0,0 +1,37 @@
+use teloxide::{prelude::*, ApiError, RequestError};
+use tracing::*;
+
+use crate::db::{self, Db};
+
+/// dptree middleware saving profile and activity of every chat that writes
+/// to the bot, reactivating it if it was marked inactive. Never stops the
+/// update from being handled.
+pub async fn track(db: Db, msg: Message) {
+    let profile = db::UserProfile {
+        chat_id: msg.chat.id.0,
+        username: msg.chat.username(),
+        first_name: msg.chat.first_name(),
+        last_name: msg.chat.last_name(),
+        language_code: msg.from().and_then(|u| u.language_code.as_deref()),
+    };
+    if let Err(e) = db.upsert_user(profile).await {
+        error!("can't save user {}: {:?}", msg.chat.id, e);
+    }
+}
+
+/// Errors meaning the bot can't write to the chat until the user writes to it
+/// again. Returns the reason stored in `users.inactive_reason`.
+pub fn inactive_reason(error: &RequestError) -> Option<&'static str> {
+    match error {
+        RequestError::Api(e) => match e {
+            ApiError::BotBlocked => Some("bot_blocked"),
+            ApiError::ChatNotFound => Some("chat_not_found"),
+            ApiError::UserDeactivated => Some("user_deactivated"),
+            ApiError::CantInitiateConversation => Some("cant_initiate_conversation"),
+            ApiError::BotKicked | ApiError::BotKickedFromSupergroup => Some("bot_kicked"),
+            ApiError::GroupDeactivated => Some("group_deactivated"),
+            _ => None,
+        },
+        _ => None,
+    }
+}